@@ -1,3 +1 @@
 
-
-
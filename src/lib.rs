@@ -1,8 +1,8 @@
 #![allow(dead_code)]
 
+pub mod error_utils;
 pub mod manager;
 pub mod node;
 pub mod scheduler;
 pub mod task;
 pub mod worker;
-pub mod error_utils;
@@ -10,17 +10,34 @@ use uuid::Uuid;
 
 use cube::manager;
 use cube::node;
-use cube::task;
+use cube::task::{self, ContainerRuntime};
 use cube::worker;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), task::RuntimeError> {
     tracing_subscriber::fmt::init();
     let (whost, wport) = ("localhost", 8901);
     let (mhost, mport) = ("localhost", 8902);
 
     info!("Starting Cube worker on {}:{}", whost, wport);
-    let worker = worker::Worker::new("Worker 1");
+    let config = worker::Config {
+        registry_auth: load_registry_auth(),
+        ..worker::Config::default()
+    };
+    let runtime = Arc::new(task::new_docker()?);
+    let processes = task::ProcessRuntime::new(
+        std::env::temp_dir().join("cube-processes"),
+        std::env::var("CUBE_CGROUP_ROOT")
+            .unwrap_or("/sys/fs/cgroup/cube".to_string())
+            .into(),
+    );
+    let modules = task::WasmRuntime::new()?;
+    let worker = worker::Worker::with_config("Worker 1", config, runtime)
+        .with_driver(task::Driver::Process, Arc::new(processes))
+        .with_driver(task::Driver::Wasm, Arc::new(modules))
+        .with_store(Arc::new(worker::WalStore::new(
+            std::env::temp_dir().join("cube-worker").join("tasks.wal"),
+        )));
     let worker = Arc::new(worker);
     let api = worker::api::setup(whost, wport, worker.clone());
 
@@ -35,11 +52,22 @@ async fn main() {
     let mapi = manager::api::setup(mhost, mport, manager.clone());
 
     manager::start_api(mapi, manager.clone()).await;
+    Ok(())
+}
+
+/// Reads registry credentials from the json file in $CUBE_REGISTRY_AUTH, a map
+/// from registry host to `{"name": .., "username": .., "password": ..}`.
+fn load_registry_auth() -> HashMap<String, task::RegistryAuth> {
+    let Ok(path) = std::env::var("CUBE_REGISTRY_AUTH") else {
+        return HashMap::new();
+    };
+    let file = std::fs::read_to_string(&path).expect("Could not read registry auth file");
+    serde_json::from_str(&file).expect("Invalid registry auth file")
 }
 
 #[allow(dead_code)]
 async fn main_old2() {
-    let w = worker::Worker::new("Worker 1");
+    let w = worker::Worker::new("Worker 1").expect("Could not connect to docker");
 
     let mut t = task::Task {
         id: Uuid::new_v4(),
@@ -51,22 +79,23 @@ async fn main_old2() {
 
     println!("starting task");
     w.add_task(t.clone());
-    let result = w.run_task().await;
-    if result.error.is_some() {
-        panic!("Error: {}", result.error.unwrap());
-    }
+    let handle = match w.run_task().await {
+        Ok(Some(handle)) => handle,
+        Ok(None) => panic!("Error: task was not started"),
+        Err(err) => panic!("Error: {}", err),
+    };
 
-    t.container_id = result.container_id;
+    t.container_id = handle.id;
     println!("task {} is running in container {} ", t.id, t.container_id);
     println!("Sleepy time");
     tokio::time::sleep(Duration::from_secs(30)).await;
 
     println!("stopping task");
-    t.state = task::State::Completed;
-    w.add_task(t.clone());
-    let result = w.run_task().await;
-    if result.error.is_some() {
-        panic!("Error: {}", result.error.unwrap());
+    if let Err(err) = w.request_stop(t.id) {
+        panic!("Error: {}", err);
+    }
+    if let Err(err) = w.run_task().await {
+        panic!("Error: {}", err);
     }
 }
 
@@ -93,12 +122,12 @@ async fn main_old() {
     println!("{:#?}", task);
     println!("{:#?}", task_event);
 
-    let worker = worker::Worker::new("Worker 1");
+    let worker = worker::Worker::new("Worker 1").expect("Could not connect to docker");
     let worker = Arc::new(worker);
 
     println!("{:#?}", worker);
     worker::collect_stats(worker.clone()).await;
-    worker.run_task().await;
+    let _ = worker.run_task().await;
     //worker.start_task(task.clone()).await;
     //worker.stop_task(task).await;
 
@@ -109,11 +138,13 @@ async fn main_old() {
         workers: Vec::new(),
         worker_task_map: Mutex::new(HashMap::new()),
         task_worker_map: Mutex::new(HashMap::new()),
+        worker_ports: Mutex::new(HashMap::new()),
         last_worker: Mutex::new(0), // to keep track of the last worker used
+        conflicts: Mutex::new(HashMap::new()),
     };
 
     println!("{:#?}", manager);
-    manager.select_worker().await;
+    manager.select_worker(&task).await;
     manager.update_tasks().await;
     manager.send_work().await;
 
@@ -130,18 +161,15 @@ async fn main_old() {
     println!("{:#?}", node);
 
     println!("Creating container");
-    let (mut docker, result) = create_container().await;
-    if result.error.is_some() {
-        panic!("Error: {}", result.error.unwrap());
-    }
+    let (docker, handle) = create_container().await;
 
     tokio::time::sleep(Duration::from_secs(5)).await;
 
     println!("Stopping container");
-    let _result = stop_container(&mut docker, &result.container_id).await;
+    let _handle = stop_container(&docker, &handle.id).await;
 }
 
-async fn create_container() -> (task::Docker, task::DockerResult) {
+async fn create_container() -> (task::Docker, task::ContainerHandle) {
     let c = task::Config {
         name: "test-container-1".to_string(),
         image: "postgres:latest".to_string(),
@@ -149,35 +177,44 @@ async fn create_container() -> (task::Docker, task::DockerResult) {
             "POSTGRES_USER=cube".to_string(),
             "POSTGRES_PASSWORD=secret".to_string(),
         ],
+        volumes: vec![task::VolumeMount {
+            kind: task::MountKind::Volume,
+            source: "cube-pgdata".to_string(),
+            target: "/var/lib/postgresql/data".to_string(),
+            read_only: false,
+        }],
         ..Default::default()
     };
 
     let dc = bollard::Docker::connect_with_local_defaults().expect("Could not connect to docker");
-    let docker = task::Docker {
-        client: dc,
-        config: c,
-    };
+    let docker = task::Docker { client: dc };
 
-    let result = docker.run().await;
-    if result.error.is_some() {
-        panic!("Error: {}", result.error.unwrap());
+    let progress = |p: &task::PullProgress| println!("pulling {}%", p.percent());
+    if let Err(err) = docker.pull(&c, &progress).await {
+        panic!("Error: {}", err);
     }
 
-    println!(
-        "Container {} is running with config {:?}",
-        result.container_id, docker.config
-    );
+    let handle = match docker.run(&c).await {
+        Ok(handle) => handle,
+        Err(err) => panic!("Error: {}", err),
+    };
+
+    println!("Container {} is running with config {:?}", handle.id, c);
 
-    (docker, result)
+    (docker, handle)
 }
 
-async fn stop_container(docker: &mut task::Docker, id: &str) -> task::DockerResult {
-    let result = docker.stop(id).await;
-    if result.error.is_some() {
-        panic!("Error: {}", result.error.unwrap());
-    }
+async fn stop_container(docker: &task::Docker, id: &str) -> task::ContainerHandle {
+    let outcome = match docker.stop(id, &task::StopOptions::default()).await {
+        Ok(outcome) => outcome,
+        Err(err) => panic!("Error: {}", err),
+    };
 
-    println!("Container {} stopped", id);
+    println!("Container {} stopped: {:?}", id, outcome);
+
+    if let Err(err) = docker.remove(id).await {
+        panic!("Error: {}", err);
+    }
 
-    result
+    task::ContainerHandle { id: id.to_string() }
 }
@@ -1,14 +1,20 @@
 use std::sync::Arc;
 
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::Response;
 use axum::routing::{delete, get, post};
 use axum::{Json, Router};
-use tracing::info;
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message as WorkerMessage;
+use tracing::{error, info};
 use uuid::Uuid;
 
 use super::manager::{self, Manager};
-use crate::task::{self, Task, TaskEvent};
+use crate::task::{ExecOptions, LogOptions, Task, TaskEvent, Transition};
+use crate::worker::client::{self, Client, ExecStream};
 
 type AppState = State<Arc<Manager>>;
 
@@ -37,6 +43,9 @@ pub fn setup(address: &str, port: u16, manager: Arc<Manager>) -> Api {
         .route("/tasks", post(start_task_handler))
         .route("/tasks", get(get_tasks))
         .route("/tasks/{task_id}", delete(stop_task))
+        .route("/tasks/{task_id}/history", get(get_task_history))
+        .route("/tasks/{task_id}/logs", get(get_task_logs))
+        .route("/tasks/{task_id}/exec", get(exec_task))
         .with_state(manager);
     Api {
         address: address.to_string(),
@@ -50,9 +59,9 @@ async fn start_task_handler(
     State(manager): AppState,
     Json(te): Json<TaskEvent>,
 ) -> (StatusCode, Json<Task>) {
-    manager.add_task(te.clone()).await;
-    info!("[MANAGER] Added task {:?}", te.task.id);
-    (StatusCode::CREATED, Json(te.task))
+    let task = manager.submit_task(te).await;
+    info!("[MANAGER] Added task {:?}", task.id);
+    (StatusCode::CREATED, Json(task))
 }
 
 async fn get_tasks(State(manager): AppState) -> Json<Vec<Task>> {
@@ -63,32 +72,119 @@ async fn stop_task(
     State(manager): AppState,
     Path(task_id): Path<Uuid>,
 ) -> Result<StatusCode, StatusCode> {
+    let task = manager.stop_task(task_id).await.map_err(|e| {
+        error!("[MANAGER] Can't stop task {:?}: {}", task_id, e);
+        match e {
+            manager::Error::TaskNotFound(_) => StatusCode::NOT_FOUND,
+            manager::Error::InvalidTransition(..) => StatusCode::CONFLICT,
+        }
+    })?;
+    info!("[MANAGER] Task {:?} is {:?}", task.id, task.state);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The state changes of a task, the manager's own and the ones reported by
+/// the worker running it, oldest first.
+async fn get_task_history(
+    State(manager): AppState,
+    Path(task_id): Path<Uuid>,
+) -> Result<Json<Vec<Transition>>, StatusCode> {
     let task_db = manager.task_db.lock().await;
-    let task_to_stop = task_db.get(&task_id);
+    let task = task_db.get(&task_id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(task.history.clone()))
+}
 
-    if task_to_stop.is_none() {
-        return Err(StatusCode::NOT_FOUND);
-    }
-    let mut task_copy = task_to_stop.unwrap().clone();
-    task_copy.state = task::State::Completed;
-    let task_id = task_copy.id.clone();
-
-    let task_event = TaskEvent {
-        id: Uuid::new_v4(),
-        state: task::State::Completed,
-        timestamp: chrono::Utc::now(),
-        task: task_copy,
-        ..Default::default()
-    };
+/// Passes the logs of a task through from the worker running it.
+async fn get_task_logs(
+    State(manager): AppState,
+    Path(task_id): Path<Uuid>,
+    Query(options): Query<LogOptions>,
+) -> Result<([(header::HeaderName, &'static str); 1], Body), StatusCode> {
+    let worker = worker_of(&manager, &task_id).await;
+    let worker = worker.ok_or(StatusCode::NOT_FOUND)?;
+
+    let logs = Client::new(&worker).get_logs(&task_id, &options).await;
+    let logs = logs.map_err(|e| {
+        error!(
+            "[MANAGER] Error getting logs of {} from {}: {:?}",
+            task_id, worker, e
+        );
+        match e {
+            client::Error::StatusCodeError(status, _) => status,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    })?;
+    let body = Body::from_stream(logs);
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body))
+}
+
+async fn worker_of(manager: &Manager, task_id: &Uuid) -> Option<String> {
+    let task_worker_map = manager.task_worker_map.lock().await;
+    task_worker_map.get(task_id).cloned()
+}
 
-    let te_id = task_event.id.clone();
+/// Opens the exec session on the worker running the task and relays the
+/// websocket frames both ways.
+async fn exec_task(
+    State(manager): AppState,
+    Path(task_id): Path<Uuid>,
+    Query(options): Query<ExecOptions>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let worker = worker_of(&manager, &task_id).await;
+    let worker = worker.ok_or(StatusCode::NOT_FOUND)?;
+
+    let upstream = Client::new(&worker).exec(&task_id, &options).await;
+    let upstream = upstream.map_err(|e| {
+        error!(
+            "[MANAGER] Error opening exec for {} on {}: {:?}",
+            task_id, worker, e
+        );
+        match e {
+            client::Error::StatusCodeError(status, _) => status,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    })?;
+    Ok(ws.on_upgrade(move |socket| proxy_exec(socket, upstream)))
+}
 
-    manager.add_task(task_event).await;
+async fn proxy_exec(socket: WebSocket, upstream: ExecStream) {
+    let (mut client_tx, mut client_rx) = socket.split();
+    let (mut worker_tx, mut worker_rx) = upstream.split();
+
+    let to_worker = async {
+        while let Some(Ok(msg)) = client_rx.next().await {
+            let msg = match msg {
+                Message::Text(text) => WorkerMessage::text(text.as_str()),
+                Message::Binary(data) => WorkerMessage::Binary(data),
+                Message::Ping(data) => WorkerMessage::Ping(data),
+                Message::Pong(data) => WorkerMessage::Pong(data),
+                Message::Close(_) => WorkerMessage::Close(None),
+            };
+            if worker_tx.send(msg).await.is_err() {
+                break;
+            }
+        }
+    };
 
-    info!(
-        "[MANAGER] Added event {:?} to stop task {:?}",
-        te_id, task_id
-    );
+    let to_client = async {
+        while let Some(Ok(msg)) = worker_rx.next().await {
+            let msg = match msg {
+                WorkerMessage::Text(text) => Message::Text(text.as_str().into()),
+                WorkerMessage::Binary(data) => Message::Binary(data),
+                WorkerMessage::Ping(data) => Message::Ping(data),
+                WorkerMessage::Pong(data) => Message::Pong(data),
+                WorkerMessage::Close(_) => Message::Close(None),
+                WorkerMessage::Frame(_) => continue,
+            };
+            if client_tx.send(msg).await.is_err() {
+                break;
+            }
+        }
+    };
 
-    Ok(StatusCode::NO_CONTENT)
+    tokio::select! {
+        _ = to_worker => (),
+        _ = to_client => (),
+    }
 }
@@ -1,15 +1,57 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
 use std::sync::Arc;
 use std::time::Duration;
 
+use reqwest::StatusCode;
 use tokio::sync::Mutex;
 
 use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::task::{self, Task, TaskEvent};
+use crate::task::{self, Actor, FailureReason, InvalidTransition, Port, Task, TaskEvent};
 use crate::worker;
 
+#[derive(Debug)]
+pub enum Error {
+    TaskNotFound(Uuid),
+    InvalidTransition(task::State, task::State),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::TaskNotFound(id) => write!(f, "task {} not found", id),
+            Error::InvalidTransition(from, to) => {
+                write!(f, "invalid state transition from {:?} to {:?}", from, to)
+            }
+        }
+    }
+}
+
+impl From<InvalidTransition> for Error {
+    fn from(e: InvalidTransition) -> Self {
+        Error::InvalidTransition(e.from, e.to)
+    }
+}
+
+/// How many times in a row workers may refuse a task for lack of room before
+/// the manager gives up on it.
+const MAX_CONFLICTS: u32 = 10;
+
+/// Whether a worker refused a task only because it has no room for it right
+/// now, in which case another worker, or the same one later, may take it.
+fn is_resource_conflict(reason: &str) -> bool {
+    matches!(reason, "port_conflict" | "ports_exhausted") || reason.starts_with("insufficient_")
+}
+
+/// Whether the state a worker reports for a task can replace the manager's.
+/// The manager only sees snapshots of the states the worker moves a task
+/// through, so steps may be missing, but the table still has to lead there.
+fn accept_update(from: task::State, to: task::State) -> bool {
+    from == to || task::is_reachable(from, to)
+}
+
 #[derive(Debug)]
 pub struct Manager {
     // Warning: many Mutexes are in use here. If more than one of them need to
@@ -22,7 +64,11 @@ pub struct Manager {
     pub workers: Vec<String>,
     pub worker_task_map: Mutex<HashMap<String, Vec<Uuid>>>,
     pub task_worker_map: Mutex<HashMap<Uuid, String>>,
+    // host ports already taken on each worker, as last reported by the worker
+    pub worker_ports: Mutex<HashMap<String, HashSet<Port>>>,
     pub last_worker: Mutex<usize>, // to keep track of the last worker used
+    // times in a row workers had no room for a task, see `MAX_CONFLICTS`
+    pub conflicts: Mutex<HashMap<Uuid, u32>>,
 }
 
 pub async fn update_tasks_loop(manager: Arc<Manager>) -> () {
@@ -30,6 +76,7 @@ pub async fn update_tasks_loop(manager: Arc<Manager>) -> () {
     loop {
         info!("[Manager] Updating tasks from {} workers", n_workers);
         manager.update_tasks().await;
+        manager.update_ports().await;
         tokio::time::sleep(Duration::from_secs(15)).await;
     }
 }
@@ -51,15 +98,97 @@ impl Manager {
         let mut pending = self.pending.lock().await;
         pending.push_back(te);
     }
+    /// Takes in a new task, it stays `Pending` until `send_work` gets to it.
+    pub async fn submit_task(&self, mut te: TaskEvent) -> Task {
+        te.task.state = task::State::Pending;
+        self.task_db
+            .lock()
+            .await
+            .insert(te.task.id, te.task.clone());
+        let task = te.task.clone();
+        self.add_task(te).await;
+        task
+    }
+
+    /// Cancels a task that was not sent to a worker yet, asks the worker to
+    /// stop it otherwise.
+    pub async fn stop_task(&self, task_id: Uuid) -> Result<Task, Error> {
+        let te = {
+            let mut task_db = self.task_db.lock().await;
+            let t = task_db
+                .get_mut(&task_id)
+                .ok_or(Error::TaskNotFound(task_id))?;
+            match t.state {
+                task::State::Stopping => return Ok(t.clone()),
+                task::State::Pending => {
+                    t.transition(
+                        task::State::Cancelled,
+                        "stopped before it was scheduled",
+                        Actor::Api,
+                    )?;
+                    return Ok(t.clone());
+                }
+                _ => t.transition(task::State::Stopping, "stop requested", Actor::Api)?,
+            }
+            TaskEvent {
+                id: Uuid::new_v4(),
+                state: task::State::Stopping,
+                timestamp: chrono::Utc::now(),
+                task: t.clone(),
+            }
+        };
+        let task = te.task.clone();
+        self.add_task(te).await;
+        Ok(task)
+    }
+
     pub async fn get_tasks(&self) -> Vec<Task> {
         let task_db = self.task_db.lock().await;
         task_db.values().cloned().collect()
     }
-    pub async fn select_worker(&self) -> String {
+    /// Round robin over the workers, skipping the ones where a host port the
+    /// task asks for is already taken. Returns `None` if no worker has room.
+    pub async fn select_worker(&self, task: &Task) -> Option<String> {
+        let wanted = task
+            .port_bindings
+            .iter()
+            .filter(|(_, binding)| binding.host_port != 0)
+            .filter_map(|(port, binding)| {
+                let protocol = port.parse::<Port>().ok()?.protocol;
+                let number = binding.host_port;
+                Some(Port { number, protocol })
+            })
+            .collect::<HashSet<Port>>();
+
+        let worker_ports = self.worker_ports.lock().await;
         let mut value = self.last_worker.lock().await;
-        let new_value = (*value + 1) % self.workers.len();
-        *value = new_value;
-        self.workers[new_value].clone()
+        for i in 1..=self.workers.len() {
+            let candidate = (*value + i) % self.workers.len();
+            let worker = &self.workers[candidate];
+            let taken = worker_ports.get(worker);
+            if taken.is_some_and(|taken| !taken.is_disjoint(&wanted)) {
+                info!("[MANAGER] Skipping worker {}, ports are taken", worker);
+                continue;
+            }
+            *value = candidate;
+            return Some(worker.clone());
+        }
+        None
+    }
+
+    pub async fn update_ports(&self) -> () {
+        for worker in &self.workers {
+            let client = worker::Client::new(worker);
+            let ports = match client.get_ports().await {
+                Ok(ports) => ports,
+                Err(e) => {
+                    error!("[MANAGER] Error getting ports from {}: {:?}", worker, e);
+                    continue;
+                }
+            };
+            let ports = ports.into_iter().map(|p| p.port).collect();
+            self.worker_ports.lock().await.insert(worker.clone(), ports);
+        }
     }
 
     pub async fn update_tasks(&self) -> () {
@@ -93,13 +222,28 @@ impl Manager {
             for task in tasks {
                 info!("[MANAGER] Attempting to update task {}", task.id);
 
-                let db_task = task_db.get_mut(&task.id);
-                db_task.map(|t| {
-                    t.state = task.state.clone();
-                    t.start_time = task.start_time.clone();
-                    t.finish_time = task.finish_time.clone();
+                if let Some(t) = task_db.get_mut(&task.id) {
+                    if !accept_update(t.state, task.state) {
+                        error!(
+                            "[MANAGER] Ignoring {:?} -> {:?} reported for task {}",
+                            t.state, task.state, task.id
+                        );
+                        continue;
+                    }
+                    t.state = task.state;
+                    t.state_reason = task.state_reason.clone();
+                    t.merge_history(&task.history);
+                    t.start_time = task.start_time;
+                    t.finish_time = task.finish_time;
                     t.container_id = task.container_id.clone();
-                });
+                    t.host_ports = task.host_ports.clone();
+                    t.exit_code = task.exit_code;
+                    t.failure_reason = task.failure_reason.clone();
+                    t.stop_outcome = task.stop_outcome;
+                    t.attempts = task.attempts;
+                    t.restart_count = task.restart_count;
+                    t.health = task.health.clone();
+                }
             }
         }
     }
@@ -111,15 +255,40 @@ impl Manager {
             return;
         }
         let te = e.unwrap();
-        let task = task::Task {
-            state: task::State::Scheduled,
-            ..te.task.clone()
-        };
+        if te.state == task::State::Stopping {
+            self.send_stop(te).await;
+            return;
+        }
 
-        let w = self.select_worker().await;
+        let known = self.task_db.lock().await.get(&te.task.id).cloned();
+        let mut task = known.unwrap_or_else(|| te.task.clone());
+        if task.state == task::State::Cancelled {
+            info!("[MANAGER] Task {:?} was cancelled, dropping it", task.id);
+            self.conflicts.lock().await.remove(&task.id);
+            return;
+        }
 
         info!("[MANAGER] pulled {:?} from queue", task);
 
+        let w = match self.select_worker(&task).await {
+            Some(w) => w,
+            None => {
+                error!("[MANAGER] No worker can take task {:?} right now", task.id);
+                self.pending.lock().await.push_back(te);
+                return;
+            }
+        };
+
+        let reason = format!("sent to worker {}", w);
+        if let Err(e) = task.transition(task::State::Scheduled, &reason, Actor::Reconciler) {
+            error!("[MANAGER] Not scheduling task {:?}: {}", task.id, e);
+            return;
+        }
+        let te = TaskEvent {
+            task: task.clone(),
+            ..te
+        };
+
         // transactional like update. This potentially holds the
         // lock for longer than its needed, but at least we dont worry about
         // inconsistent state.
@@ -152,15 +321,108 @@ impl Manager {
                     self.pending.lock().await.push_back(te);
                     return;
                 }
+                // the worker is out of ports or room for the task, try again once
+                // our view of it is refreshed, unless no worker had room for too long
+                worker::client::Error::StatusCodeError(StatusCode::CONFLICT, ref body)
+                    if e.reason().is_some_and(|r| is_resource_conflict(&r)) =>
+                {
+                    error!("[MANAGER] Worker {} rejected task: {}", w, body);
+                    self.unassign(task.id, &w).await;
+                    let conflicts = {
+                        let mut conflicts = self.conflicts.lock().await;
+                        let n = conflicts.entry(task.id).or_insert(0);
+                        *n += 1;
+                        *n
+                    };
+                    if conflicts < MAX_CONFLICTS {
+                        self.pending.lock().await.push_back(te);
+                        return;
+                    }
+                    let message = format!(
+                        "no worker had room for it after {} tries, {}",
+                        conflicts,
+                        refusal(&w, &e)
+                    );
+                    self.reject(task.id, message).await;
+                    return;
+                }
                 _ => {
                     error!("[MANAGER] Error sending task to worker: {:?}", e);
+                    self.unassign(task.id, &w).await;
+                    self.reject(task.id, refusal(&w, &e)).await;
                     return;
                 }
             },
         };
+        self.conflicts.lock().await.remove(&task.id);
         info!("[MANAGER] Task sent to worker: {:?}", task);
     }
 
+    /// Forgets that a task was sent to `worker`, once the worker refused it.
+    async fn unassign(&self, task_id: Uuid, worker: &str) {
+        let mut worker_task_map = self.worker_task_map.lock().await;
+        let mut task_worker_map = self.task_worker_map.lock().await;
+        if let Some(tasks) = worker_task_map.get_mut(worker) {
+            tasks.retain(|id| *id != task_id);
+        }
+        task_worker_map.remove(&task_id);
+    }
+
+    /// Fails a task that trying again won't get onto a worker.
+    async fn reject(&self, task_id: Uuid, message: String) {
+        self.conflicts.lock().await.remove(&task_id);
+        let mut task_db = self.task_db.lock().await;
+        let Some(t) = task_db.get_mut(&task_id) else {
+            return;
+        };
+        if let Err(e) = t.transition(task::State::Failed, &message, Actor::Reconciler) {
+            error!("[MANAGER] Task {:?}: {}", task_id, e);
+            return;
+        }
+        t.failure_reason = Some(FailureReason::Rejected(message));
+        t.finish_time = Some(chrono::Utc::now());
+    }
+
+    async fn send_stop(&self, te: TaskEvent) {
+        let w = self.task_worker_map.lock().await.get(&te.task.id).cloned();
+        let Some(w) = w else {
+            error!("[MANAGER] Task {:?} is not on any worker", te.task.id);
+            self.cancel_stopping(te.task.id, "stopped before a worker took it")
+                .await;
+            return;
+        };
+
+        match worker::Client::new(&w).stop_task(&te.task.id).await {
+            Ok(()) => info!("[MANAGER] Asked {} to stop task {:?}", w, te.task.id),
+            Err(worker::client::Error::ErrorReachingWorker(e)) => {
+                error!("[MANAGER] Error reaching worker: {:?}", e);
+                self.pending.lock().await.push_back(te);
+            }
+            // the start never made it to the worker
+            Err(worker::client::Error::StatusCodeError(StatusCode::NOT_FOUND, _)) => {
+                info!("[MANAGER] Worker {} doesn't know task {:?}", w, te.task.id);
+                let reason = format!("stopped before worker {} got it", w);
+                self.cancel_stopping(te.task.id, &reason).await;
+            }
+            Err(e) => error!("[MANAGER] Error stopping task {:?}: {:?}", te.task.id, e),
+        }
+    }
+
+    /// Cancels a task being stopped that never got to a worker, nothing will
+    /// ever report it stopped otherwise.
+    async fn cancel_stopping(&self, task_id: Uuid, reason: &str) {
+        let mut task_db = self.task_db.lock().await;
+        let Some(t) = task_db.get_mut(&task_id) else {
+            return;
+        };
+        if t.state != task::State::Stopping {
+            return;
+        }
+        if let Err(e) = t.transition(task::State::Cancelled, reason, Actor::Reconciler) {
+            error!("[MANAGER] Task {:?}: {}", task_id, e);
+        }
+    }
+
     pub fn new(workers: Vec<String>) -> Self {
         let worker_task_map = workers
             .iter()
@@ -174,7 +436,184 @@ impl Manager {
             workers,
             worker_task_map: Mutex::new(worker_task_map),
             task_worker_map: Mutex::new(HashMap::new()),
+            worker_ports: Mutex::new(HashMap::new()),
             last_worker: Mutex::new(0),
+            conflicts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// What `worker` said when it refused a task.
+fn refusal(worker: &str, e: &worker::client::Error) -> String {
+    match e {
+        worker::client::Error::StatusCodeError(status, body) => {
+            format!(
+                "worker {} refused the task with {}: {}",
+                worker, status, body
+            )
+        }
+        e => format!("worker {} refused the task: {:?}", worker, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::FakeRuntime;
+    use crate::worker::Worker;
+
+    /// Runs a worker on a fake runtime behind its api, returns it with the
+    /// address the manager reaches it at.
+    async fn fake_worker() -> (Arc<Worker>, String) {
+        let runtime = Arc::new(FakeRuntime::new());
+        let config = worker::worker::test_config();
+        let w = Arc::new(Worker::with_config("test-worker", config, runtime));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let api = worker::api::setup("127.0.0.1", 0, w.clone());
+        tokio::spawn(api.serve(listener));
+        (w, address)
+    }
+
+    fn new_task(name: &str) -> TaskEvent {
+        let task = Task {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            image: "strm/helloworld-http".to_string(),
+            ..Default::default()
+        };
+        TaskEvent {
+            id: Uuid::new_v4(),
+            state: task::State::Scheduled,
+            timestamp: chrono::Utc::now(),
+            task,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_work_handles_rejections() {
+        let (_w, address) = fake_worker().await;
+        let m = Manager::new(vec![address.clone()]);
+        let binding = task::PortBinding {
+            host_port: 8080,
+            ..Default::default()
+        };
+        let mut first = new_task("first");
+        first.task.port_bindings = HashMap::from([("80/tcp".to_string(), binding)]);
+        let mut second = new_task("second");
+        second.task.port_bindings = first.task.port_bindings.clone();
+        let mut wasm = new_task("wasm");
+        wasm.task.driver = task::Driver::Wasm;
+        for te in [&first, &second, &wasm] {
+            m.submit_task(te.clone()).await;
+        }
+
+        m.send_work().await;
+        m.send_work().await;
+        // the port is taken, the task waits for another try
+        {
+            let pending = m.pending.lock().await;
+            assert_eq!(pending.len(), 2);
+            assert_eq!(pending[1].task.id, second.task.id);
+        }
+        assert_eq!(
+            m.worker_task_map.lock().await[&address],
+            vec![first.task.id]
+        );
+        assert!(!m.task_worker_map.lock().await.contains_key(&second.task.id));
+
+        // the worker has no wasm driver, trying again won't help
+        m.send_work().await;
+        let pending = m.pending.lock().await;
+        assert_eq!(pending.len(), 1);
+        let t = m.task_db.lock().await[&wasm.task.id].clone();
+        assert_eq!(t.state, task::State::Failed);
+        assert!(matches!(t.failure_reason, Some(FailureReason::Rejected(_))));
+        assert!(!m.task_worker_map.lock().await.contains_key(&wasm.task.id));
+    }
+
+    #[tokio::test]
+    async fn test_send_work_gives_up_on_tasks_that_fit_nowhere() {
+        let (_w, address) = fake_worker().await;
+        let m = Manager::new(vec![address]);
+        let mut huge = new_task("huge");
+        huge.task.cpu = 1e6;
+        m.submit_task(huge.clone()).await;
+
+        for _ in 1..MAX_CONFLICTS {
+            m.send_work().await;
+            assert_eq!(m.pending.lock().await.len(), 1);
+        }
+        m.send_work().await;
+        assert!(m.pending.lock().await.is_empty());
+        assert!(m.conflicts.lock().await.is_empty());
+        let t = m.task_db.lock().await[&huge.task.id].clone();
+        assert_eq!(t.state, task::State::Failed);
+        let Some(FailureReason::Rejected(message)) = t.failure_reason else {
+            panic!("not rejected: {:?}", t.failure_reason);
+        };
+        assert!(message.contains("insufficient_cpu"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_update_tasks() {
+        let (w, address) = fake_worker().await;
+        let m = Manager::new(vec![address]);
+        let te = new_task("stopped");
+        m.submit_task(te.clone()).await;
+        m.send_work().await;
+        w.run_task().await.unwrap();
+        w.request_stop(te.task.id).unwrap();
+        w.run_task().await.unwrap();
+
+        m.update_tasks().await;
+        let t = m.task_db.lock().await[&te.task.id].clone();
+        assert_eq!(t.state, task::State::Completed);
+        assert_eq!(t.container_id, "fake-1");
+        assert_eq!(t.stop_outcome, Some(task::StopOutcome::Exited));
+        assert!(t.finish_time.is_some());
+    }
+
+    #[test]
+    fn test_accept_update() {
+        use task::State as S;
+        // a worker that ran and finished the task between two polls
+        assert!(accept_update(S::Scheduled, S::Completed));
+        assert!(accept_update(S::Restarting, S::Completed));
+        assert!(accept_update(S::Running, S::Running));
+        // a stop the worker didn't get to yet
+        assert!(!accept_update(S::Stopping, S::Running));
+        assert!(!accept_update(S::Stopping, S::Restarting));
+        assert!(!accept_update(S::Running, S::Pending));
+        assert!(!accept_update(S::Completed, S::Running));
+    }
+
+    #[tokio::test]
+    async fn test_stop_task_the_worker_never_got() {
+        let (_w, address) = fake_worker().await;
+        let m = Manager::new(vec![address.clone()]);
+        let lost = new_task("lost");
+        let unsent = new_task("unsent");
+        for te in [&lost, &unsent] {
+            m.submit_task(te.clone()).await;
+            let mut task_db = m.task_db.lock().await;
+            let t = task_db.get_mut(&te.task.id).unwrap();
+            t.transition(task::State::Scheduled, "sent", Actor::Reconciler)
+                .unwrap();
+        }
+        m.pending.lock().await.clear();
+        // sent to the worker as far as the manager knows, but it never got there
+        m.task_worker_map
+            .lock()
+            .await
+            .insert(lost.task.id, address.clone());
+
+        for te in [&lost, &unsent] {
+            let t = m.stop_task(te.task.id).await.unwrap();
+            assert_eq!(t.state, task::State::Stopping);
+            m.send_work().await;
+            let t = m.task_db.lock().await[&te.task.id].clone();
+            assert_eq!(t.state, task::State::Cancelled);
         }
     }
 }
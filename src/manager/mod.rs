@@ -1,4 +1,5 @@
 pub mod api;
+#[allow(clippy::module_inception)]
 pub mod manager;
 
 pub use api::start_api;
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod node;
 
 pub use node::Node;
@@ -1 +1,2 @@
+#[allow(clippy::module_inception)]
 mod scheduler;
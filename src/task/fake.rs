@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use tokio::io::AsyncReadExt;
+
+use super::runtime::{
+    ContainerHandle, ContainerInfo, ContainerRuntime, ContainerStats, ContainerSummary,
+    ExecOptions, ExecSession, LayerProgress, LogChunk, LogOptions, LogSource, LogStream,
+    PullProgress, PullProgressFn, RuntimeError, StopOptions, StopOutcome, VolumeInfo,
+};
+use super::task::{Config, MountKind, PortBinding};
+
+// where docker starts handing out ephemeral host ports
+const FIRST_DYNAMIC_PORT: u16 = 32768;
+
+/// In memory runtime. Container ids are handed out in order ("fake-1",
+/// "fake-2", ...) so tests can predict them.
+#[derive(Debug, Default)]
+pub struct FakeRuntime {
+    state: Mutex<FakeState>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    next_id: u64,
+    next_port: u16,
+    containers: HashMap<String, FakeContainer>,
+    volumes: HashMap<String, VolumeInfo>,
+    pulls: Vec<Config>,
+    fail_next_pull: Option<RuntimeError>,
+    fail_next_run: Option<RuntimeError>,
+    fail_next_start: Option<RuntimeError>,
+    // exec id -> container
+    execs: HashMap<String, String>,
+    resizes: Vec<(String, u16, u16)>,
+    stops: Vec<(String, StopOptions)>,
+    // how long `pull`, `run` and `stop` take
+    delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeContainer {
+    pub config: Config,
+    pub running: bool,
+    pub exit_code: Option<i64>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub ports: HashMap<String, PortBinding>,
+    pub logs: Vec<LogChunk>,
+    // what the commands run with `exec` exit with
+    pub exec_exit_code: i64,
+    // doesn't exit on the stop signal, only on SIGKILL
+    pub ignores_stop_signal: bool,
+    // what `stats` reports
+    pub stats: ContainerStats,
+}
+
+impl FakeRuntime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The next call to `pull` fails with `err`.
+    pub fn fail_next_pull(&self, err: RuntimeError) {
+        self.state.lock().unwrap().fail_next_pull = Some(err);
+    }
+
+    /// The next call to `run` fails with `err` instead of creating a container.
+    pub fn fail_next_run(&self, err: RuntimeError) {
+        self.state.lock().unwrap().fail_next_run = Some(err);
+    }
+
+    /// The next call to `run` creates a container that fails to start with
+    /// `err`, and removes it again.
+    pub fn fail_next_start(&self, err: RuntimeError) {
+        self.state.lock().unwrap().fail_next_start = Some(err);
+    }
+
+    /// Simulates the process inside the container exiting on its own.
+    pub fn exit(&self, id: &str, exit_code: i64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.containers.get_mut(id) {
+            c.running = false;
+            c.exit_code = Some(exit_code);
+            c.finished_at = Some(Utc::now());
+        }
+    }
+
+    pub fn push_log(&self, id: &str, source: LogSource, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.containers.get_mut(id) {
+            c.logs.push(LogChunk {
+                source,
+                timestamp: Some(Utc::now()),
+                message: format!("{}\n", line),
+            });
+        }
+    }
+
+    pub fn set_exec_exit_code(&self, id: &str, exit_code: i64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.containers.get_mut(id) {
+            c.exec_exit_code = exit_code;
+        }
+    }
+
+    /// The container keeps running until it is killed when stopped.
+    pub fn ignore_stop_signal(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.containers.get_mut(id) {
+            c.ignores_stop_signal = true;
+        }
+    }
+
+    pub fn set_stats(&self, id: &str, stats: ContainerStats) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(c) = state.containers.get_mut(id) {
+            c.stats = stats;
+        }
+    }
+
+    /// Makes `pull`, `run` and `stop` take `delay`, like an image that is slow
+    /// to pull or a container that is slow to start or to stop.
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    /// The `stop` calls so far, as (container id, options).
+    pub fn stops(&self) -> Vec<(String, StopOptions)> {
+        self.state.lock().unwrap().stops.clone()
+    }
+
+    pub fn container(&self, id: &str) -> Option<FakeContainer> {
+        self.state.lock().unwrap().containers.get(id).cloned()
+    }
+
+    /// The configs `pull` was called with, oldest first.
+    pub fn pulls(&self) -> Vec<Config> {
+        self.state.lock().unwrap().pulls.clone()
+    }
+
+    pub fn container_count(&self) -> usize {
+        self.state.lock().unwrap().containers.len()
+    }
+
+    /// The `resize_exec` calls so far, as (exec id, rows, cols).
+    pub fn resizes(&self) -> Vec<(String, u16, u16)> {
+        self.state.lock().unwrap().resizes.clone()
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for FakeRuntime {
+    async fn pull(&self, config: &Config, progress: &PullProgressFn) -> Result<(), RuntimeError> {
+        let delay = self.state.lock().unwrap().delay;
+        tokio::time::sleep(delay).await;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.pulls.push(config.clone());
+            if let Some(err) = state.fail_next_pull.take() {
+                return Err(err);
+            }
+        }
+
+        // every image is a single layer that is already there
+        let layer = LayerProgress {
+            status: "Already exists".to_string(),
+            current: 0,
+            total: 0,
+        };
+        let pull = PullProgress {
+            layers: HashMap::from([(config.image.clone(), layer)]),
+        };
+        progress(&pull);
+        Ok(())
+    }
+
+    async fn run(&self, config: &Config) -> Result<ContainerHandle, RuntimeError> {
+        let delay = self.state.lock().unwrap().delay;
+        tokio::time::sleep(delay).await;
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.fail_next_run.take() {
+            return Err(err);
+        }
+
+        let name_taken = state
+            .containers
+            .values()
+            .any(|c| !config.name.is_empty() && c.config.name == config.name);
+        if name_taken {
+            let err = format!("container name {:?} is already in use", config.name);
+            return Err(RuntimeError::CreateConflict(err));
+        }
+
+        // like docker, named volumes are created the first time they are used
+        for v in &config.volumes {
+            if v.kind == MountKind::Volume && !state.volumes.contains_key(&v.source) {
+                let volume = fake_volume(&v.source);
+                state.volumes.insert(v.source.clone(), volume);
+            }
+        }
+
+        let mut ports = HashMap::new();
+        for (port, binding) in &config.port_bindings {
+            let mut binding = binding.clone();
+            if binding.host_port == 0 {
+                binding.host_port = FIRST_DYNAMIC_PORT + state.next_port;
+                state.next_port += 1;
+            }
+            ports.insert(port.clone(), binding);
+        }
+
+        state.next_id += 1;
+        let id = format!("fake-{}", state.next_id);
+        if let Some(err) = state.fail_next_start.take() {
+            return Err(err);
+        }
+        let container = FakeContainer {
+            config: config.clone(),
+            running: true,
+            exit_code: None,
+            finished_at: None,
+            ports,
+            logs: Vec::new(),
+            exec_exit_code: 0,
+            ignores_stop_signal: false,
+            stats: ContainerStats::default(),
+        };
+        state.containers.insert(id.clone(), container);
+        Ok(ContainerHandle { id })
+    }
+
+    /// Doesn't wait for the grace period, a container that ignores the stop
+    /// signal is killed right away.
+    async fn stop(&self, id: &str, options: &StopOptions) -> Result<StopOutcome, RuntimeError> {
+        let delay = self.state.lock().unwrap().delay;
+        tokio::time::sleep(delay).await;
+        let mut state = self.state.lock().unwrap();
+        state.stops.push((id.to_string(), options.clone()));
+        let c = state.containers.get_mut(id).ok_or_else(|| not_found(id))?;
+        if !c.running {
+            return Ok(StopOutcome::Exited);
+        }
+        c.running = false;
+        c.finished_at = Some(Utc::now());
+        if c.ignores_stop_signal {
+            c.exit_code = Some(137);
+            return Ok(StopOutcome::Killed);
+        }
+        c.exit_code = Some(0);
+        Ok(StopOutcome::Exited)
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), RuntimeError> {
+        let mut state = self.state.lock().unwrap();
+        state.containers.remove(id).ok_or_else(|| not_found(id))?;
+        Ok(())
+    }
+
+    async fn inspect(&self, id: &str) -> Result<ContainerInfo, RuntimeError> {
+        let state = self.state.lock().unwrap();
+        let c = state.containers.get(id).ok_or_else(|| not_found(id))?;
+        Ok(ContainerInfo {
+            id: id.to_string(),
+            running: c.running,
+            exit_code: c.exit_code,
+            oom_killed: false,
+            finished_at: c.finished_at,
+            ports: c.ports.clone(),
+        })
+    }
+
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>, RuntimeError> {
+        let state = self.state.lock().unwrap();
+        let containers = state
+            .containers
+            .iter()
+            .filter(|(_, c)| c.config.labels.contains_key(label))
+            .map(|(id, c)| ContainerSummary {
+                id: id.clone(),
+                name: c.config.name.clone(),
+                running: c.running,
+                labels: c.config.labels.clone(),
+            });
+        Ok(containers.collect())
+    }
+
+    async fn stats(&self, id: &str) -> Result<ContainerStats, RuntimeError> {
+        let state = self.state.lock().unwrap();
+        let c = state.containers.get(id).ok_or_else(|| not_found(id))?;
+        Ok(ContainerStats {
+            sampled_at: Utc::now(),
+            ..c.stats.clone()
+        })
+    }
+
+    /// Returns what was logged so far, `follow` is ignored.
+    async fn logs(&self, id: &str, options: &LogOptions) -> Result<LogStream, RuntimeError> {
+        let state = self.state.lock().unwrap();
+        let c = state.containers.get(id).ok_or_else(|| not_found(id))?;
+        let mut chunks = c
+            .logs
+            .iter()
+            .filter(|chunk| options.matches(chunk))
+            .cloned()
+            .collect::<Vec<_>>();
+        if let Some(tail) = options.tail {
+            chunks = chunks.split_off(chunks.len().saturating_sub(tail as usize));
+        }
+        Ok(futures::stream::iter(chunks.into_iter().map(Ok)).boxed())
+    }
+
+    /// Sessions echo their input back, like a tty with nothing running in it.
+    async fn exec(&self, id: &str, options: &ExecOptions) -> Result<ExecSession, RuntimeError> {
+        let mut state = self.state.lock().unwrap();
+        let c = state.containers.get(id).ok_or_else(|| not_found(id))?;
+        if !c.running {
+            let err = format!("container {} is not running", id);
+            return Err(RuntimeError::Other(err));
+        }
+        if options.command().is_empty() {
+            return Err(RuntimeError::Other("no command to exec".to_string()));
+        }
+
+        state.next_id += 1;
+        let exec_id = format!("fake-exec-{}", state.next_id);
+        state.execs.insert(exec_id.clone(), id.to_string());
+
+        let (input, output) = tokio::io::duplex(1024);
+        let output = futures::stream::unfold(output, |mut output| async move {
+            let mut buf = vec![0; 1024];
+            match output.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(buf), output))
+                }
+                Err(e) => Some((Err(RuntimeError::Other(e.to_string())), output)),
+            }
+        });
+        Ok(ExecSession {
+            id: exec_id,
+            output: output.boxed(),
+            input: Box::pin(input),
+        })
+    }
+
+    async fn exec_exit_code(&self, exec_id: &str) -> Result<Option<i64>, RuntimeError> {
+        let state = self.state.lock().unwrap();
+        let container = state
+            .execs
+            .get(exec_id)
+            .ok_or_else(|| RuntimeError::NotFound(format!("no such exec instance: {}", exec_id)))?;
+        let code = state
+            .containers
+            .get(container)
+            .map_or(0, |c| c.exec_exit_code);
+        Ok(Some(code))
+    }
+
+    async fn resize_exec(&self, exec_id: &str, rows: u16, cols: u16) -> Result<(), RuntimeError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.execs.contains_key(exec_id) {
+            let err = format!("no such exec instance: {}", exec_id);
+            return Err(RuntimeError::NotFound(err));
+        }
+        state.resizes.push((exec_id.to_string(), rows, cols));
+        Ok(())
+    }
+
+    async fn list_volumes(&self) -> Result<Vec<VolumeInfo>, RuntimeError> {
+        let state = self.state.lock().unwrap();
+        Ok(state.volumes.values().cloned().collect())
+    }
+
+    async fn create_volume(&self, name: &str) -> Result<VolumeInfo, RuntimeError> {
+        let mut state = self.state.lock().unwrap();
+        let volume = state
+            .volumes
+            .entry(name.to_string())
+            .or_insert_with(|| fake_volume(name));
+        Ok(volume.clone())
+    }
+
+    async fn remove_volume(&self, name: &str) -> Result<(), RuntimeError> {
+        let mut state = self.state.lock().unwrap();
+        let in_use = state.containers.values().any(|c| {
+            c.config
+                .volumes
+                .iter()
+                .any(|v| v.kind == MountKind::Volume && v.source == name)
+        });
+        if in_use {
+            return Err(RuntimeError::InUse(format!("volume {} is in use", name)));
+        }
+        state
+            .volumes
+            .remove(name)
+            .ok_or_else(|| RuntimeError::NotFound(format!("no such volume: {}", name)))?;
+        Ok(())
+    }
+}
+
+fn fake_volume(name: &str) -> VolumeInfo {
+    VolumeInfo {
+        name: name.to_string(),
+        driver: "local".to_string(),
+        mountpoint: format!("/fake/volumes/{}/_data", name),
+    }
+}
+
+fn not_found(id: &str) -> RuntimeError {
+    RuntimeError::NotFound(format!("no such container: {}", id))
+}
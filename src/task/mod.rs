@@ -1,7 +1,27 @@
+mod fake;
+mod output;
+mod probe;
+mod process;
+mod runtime;
 mod state_machine;
+#[allow(clippy::module_inception)]
 mod task;
+mod wasm;
 
-pub use state_machine::{is_valid_transition, state_transition_map};
+pub use fake::{FakeContainer, FakeRuntime};
+pub use probe::{Health, Probe, ProbeAction, ProbeStatus};
+pub use process::ProcessRuntime;
+pub use runtime::{
+    ContainerHandle, ContainerInfo, ContainerRuntime, ContainerStats, ContainerSummary,
+    ExecOptions, ExecSession, LayerProgress, LogChunk, LogOptions, LogSource, LogStream,
+    PullProgress, PullProgressFn, RuntimeError, StopOptions, StopOutcome, VolumeInfo,
+};
+pub use state_machine::{
+    is_reachable, is_valid_transition, state_transition_map, InvalidTransition,
+};
 pub use task::{
-    new_config, new_docker, Config, Docker, DockerResult, Port, State, Task, TaskEvent,
+    new_config, new_docker, registry_host, split_image, Actor, Config, Docker, Driver,
+    FailureReason, MountKind, Port, PortBinding, Protocol, RegistryAuth, RestartPolicy, State,
+    Task, TaskEvent, Transition, VolumeMount, TASK_ID_LABEL, TASK_SPEC_LABEL,
 };
+pub use wasm::WasmRuntime;
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use futures::stream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::broadcast;
+
+use super::runtime::{LogChunk, LogOptions, LogSource, LogStream};
+
+// how much output is kept per task, the oldest goes first
+const MAX_BYTES: usize = 4 * 1024 * 1024;
+// longer lines are split, like docker does
+const MAX_LINE: usize = 16 * 1024;
+
+/// The output of a task the worker runs itself instead of through docker,
+/// kept in memory for `logs` and passed on to followers as it comes. Only the
+/// last `max_bytes` of it are kept.
+#[derive(Debug)]
+pub struct Output {
+    chunks: VecDeque<LogChunk>,
+    bytes: usize,
+    max_bytes: usize,
+    // dropped once there is nothing more to come, which ends the followers
+    live: Option<broadcast::Sender<LogChunk>>,
+}
+
+impl Output {
+    pub fn new() -> Self {
+        Self::with_limit(MAX_BYTES)
+    }
+
+    pub fn with_limit(max_bytes: usize) -> Self {
+        let (live, _) = broadcast::channel(1024);
+        Output {
+            chunks: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            live: Some(live),
+        }
+    }
+
+    pub fn push(&mut self, source: LogSource, message: &[u8]) {
+        let chunk = LogChunk {
+            source,
+            timestamp: Some(Utc::now()),
+            message: String::from_utf8_lossy(message).into_owned(),
+        };
+        if let Some(live) = self.live.as_ref() {
+            // nobody following is fine
+            let _ = live.send(chunk.clone());
+        }
+        self.bytes += chunk.message.len();
+        self.chunks.push_back(chunk);
+        while self.bytes > self.max_bytes {
+            let Some(oldest) = self.chunks.pop_front() else {
+                break;
+            };
+            self.bytes -= oldest.message.len();
+        }
+    }
+
+    pub fn close(&mut self) {
+        self.live = None;
+    }
+
+    /// What was written so far, and with `follow` what is still to come.
+    pub fn stream(&self, options: &LogOptions) -> LogStream {
+        let mut chunks: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|c| options.matches(c))
+            .cloned()
+            .collect();
+        if let Some(tail) = options.tail {
+            chunks = chunks.split_off(chunks.len().saturating_sub(tail as usize));
+        }
+        let chunks = stream::iter(chunks.into_iter().map(Ok));
+
+        let live = match self.live.as_ref() {
+            Some(live) if options.follow => live.subscribe(),
+            _ => return Box::pin(chunks),
+        };
+        let options = options.clone();
+        let live = stream::unfold(live, move |mut live| {
+            let options = options.clone();
+            async move {
+                loop {
+                    match live.recv().await {
+                        Ok(chunk) if options.matches(&chunk) => return Some((Ok(chunk), live)),
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Box::pin(futures::StreamExt::chain(chunks, live))
+    }
+}
+
+/// Adds the lines `reader` produces to `output`, until it is closed. Lines
+/// longer than `MAX_LINE` come in several chunks.
+pub async fn collect<R: AsyncRead + Unpin>(
+    output: Arc<Mutex<Output>>,
+    source: LogSource,
+    reader: R,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_LINE as u64);
+        match limited.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => output.lock().unwrap().push(source, &line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+
+    async fn messages(chunks: LogStream) -> Vec<String> {
+        chunks.map(|c| c.unwrap().message).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_output_limit() {
+        let mut output = Output::with_limit(9);
+        output.push(LogSource::Stdout, b"one\n");
+        output.push(LogSource::Stdout, b"two\n");
+        assert_eq!(output.bytes, 8);
+        output.push(LogSource::Stderr, b"three\n");
+        let chunks = output.stream(&LogOptions::default());
+        assert_eq!(messages(chunks).await, ["three\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_collect_splits_long_lines() {
+        let output = Arc::new(Mutex::new(Output::new()));
+        let long = vec![b'x'; MAX_LINE + 10];
+        let input = [long.as_slice(), b"\nshort\n"].concat();
+        collect(output.clone(), LogSource::Stdout, input.as_slice()).await;
+
+        let chunks = output.lock().unwrap().stream(&LogOptions::default());
+        let lengths: Vec<_> = messages(chunks).await.iter().map(|m| m.len()).collect();
+        assert_eq!(lengths, [MAX_LINE, 11, 6]);
+    }
+}
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::task::Port;
+
+/// A check the worker runs against a task's container every `interval_secs`.
+/// It is only considered failed after `failure_threshold` failures in a row.
+///
+/// ```json
+/// {"type": "http", "port": {"number": 80, "protocol": "Tcp"}, "path": "/health"}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Probe {
+    #[serde(flatten)]
+    pub action: ProbeAction,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    1
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+/// What a probe does. Ports are container ports, the worker goes through the
+/// host port they are published on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ProbeAction {
+    // passes on a 2xx or 3xx response
+    Http { port: Port, path: String },
+    Tcp { port: Port },
+    // passes when the command exits with 0, split on whitespace like `ExecOptions`
+    Exec { cmd: String },
+}
+
+/// The outcome of the recent runs of one probe.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProbeStatus {
+    // None until the probe passed or reached its failure threshold
+    pub healthy: Option<bool>,
+    // failures in a row
+    pub failures: u32,
+    pub last_error: Option<String>,
+    pub last_check: Option<DateTime<Utc>>,
+}
+
+impl ProbeStatus {
+    /// Records one run of `probe`.
+    pub fn record(&mut self, probe: &Probe, result: Result<(), String>) {
+        self.last_check = Some(Utc::now());
+        match result {
+            Ok(()) => {
+                self.healthy = Some(true);
+                self.failures = 0;
+                self.last_error = None;
+            }
+            Err(e) => {
+                self.failures += 1;
+                self.last_error = Some(e);
+                if self.failures >= probe.failure_threshold {
+                    self.healthy = Some(false);
+                }
+            }
+        }
+    }
+}
+
+/// What the worker's probes found out about a task.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Health {
+    pub liveness: ProbeStatus,
+    pub readiness: ProbeStatus,
+}
@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::output::{self, Output};
+use super::runtime::{
+    cpu_percent, ContainerHandle, ContainerInfo, ContainerRuntime, ContainerStats,
+    ContainerSummary, ExecOptions, ExecSession, LogOptions, LogSource, LogStream, PullProgressFn,
+    RuntimeError, StopOptions, StopOutcome, VolumeInfo,
+};
+use super::task::Config;
+
+// cpu.max takes a quota per period, both in microseconds
+const CPU_PERIOD: u64 = 100_000;
+// what docker uses when the image doesn't set a PATH
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// a signal number for the task waiting on the process, and where the result of
+// sending it goes
+type SignalRequest = (i32, oneshot::Sender<io::Result<()>>);
+
+/// Runs tasks as plain processes on the host, for static binaries that don't
+/// need an image. A task with an `image` runs chrooted into it, the image
+/// being the path of a rootfs tarball that `pull` unpacks under `dir`.
+///
+/// Every process gets its own cgroup under `cgroup_root`, where `cpu` and
+/// `memory` are applied as `cpu.max` and `memory.max`. The worker needs to be
+/// allowed to write there, either as root or with the subtree delegated to
+/// it. Processes share the host's network, port bindings are not applied, and
+/// volumes and `exec` are not supported.
+#[derive(Debug)]
+pub struct ProcessRuntime {
+    dir: PathBuf,
+    cgroup_root: PathBuf,
+    procs: Arc<Mutex<HashMap<String, Proc>>>,
+}
+
+#[derive(Debug)]
+struct Proc {
+    name: String,
+    labels: HashMap<String, String>,
+    // to the task that waits for the process, see `signal`
+    signals: mpsc::UnboundedSender<SignalRequest>,
+    running: bool,
+    exit_code: Option<i64>,
+    finished_at: Option<DateTime<Utc>>,
+    cgroup: Option<PathBuf>,
+    output: Arc<Mutex<Output>>,
+    exited: watch::Receiver<bool>,
+    // cpu time at the previous `stats`
+    last_cpu: Option<(Duration, DateTime<Utc>)>,
+}
+
+impl ProcessRuntime {
+    pub fn new(dir: PathBuf, cgroup_root: PathBuf) -> Self {
+        ProcessRuntime {
+            dir,
+            cgroup_root,
+            procs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn rootfs(&self, image: &str) -> Option<PathBuf> {
+        if image.is_empty() {
+            return None;
+        }
+        let name = image.trim_start_matches('/').replace('/', "_");
+        Some(self.dir.join("rootfs").join(name))
+    }
+
+    /// Creates the process's cgroup and applies its limits.
+    fn create_cgroup(&self, id: &str, config: &Config) -> io::Result<PathBuf> {
+        fs::create_dir_all(&self.cgroup_root)?;
+        // the controllers have to be enabled here for the process's cgroup to get them
+        fs::write(
+            self.cgroup_root.join("cgroup.subtree_control"),
+            "+cpu +memory",
+        )?;
+        let cgroup = self.cgroup_root.join(id);
+        fs::create_dir(&cgroup)?;
+        if config.cpu > 0.0 {
+            let quota = (config.cpu * CPU_PERIOD as f64) as u64;
+            fs::write(cgroup.join("cpu.max"), format!("{} {}", quota, CPU_PERIOD))?;
+        }
+        if config.memory > 0 {
+            fs::write(cgroup.join("memory.max"), config.memory.to_string())?;
+        }
+        Ok(cgroup)
+    }
+
+    fn command(&self, config: &Config, cgroup: Option<&Path>) -> io::Result<Command> {
+        let argv: Vec<&String> = config.entrypoint.iter().chain(&config.cmd).collect();
+        let mut cmd = Command::new(argv[0]);
+        cmd.args(&argv[1..])
+            .env_clear()
+            .env("PATH", DEFAULT_PATH)
+            .envs(config.env.iter().filter_map(|e| e.split_once('=')))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let procs = match cgroup {
+            Some(cgroup) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(cgroup.join("cgroup.procs"))?,
+            ),
+            None => None,
+        };
+        let rootfs = self.rootfs(&config.image);
+        if rootfs.is_none() && !config.working_dir.is_empty() {
+            cmd.current_dir(&config.working_dir);
+        }
+        let rootfs = match rootfs {
+            Some(rootfs) => Some(CString::new(rootfs.as_os_str().as_bytes())?),
+            None => None,
+        };
+        let chroot_dir = Path::new("/").join(&config.working_dir);
+        let chroot_dir = CString::new(chroot_dir.as_os_str().as_bytes())?;
+
+        // SAFETY: runs in the forked child before exec, where only async-signal
+        // safe calls are allowed. It only makes syscalls, on paths turned into C
+        // strings beforehand, so nothing is allocated.
+        unsafe {
+            cmd.pre_exec(move || {
+                // "0" moves the writing process, it joins the cgroup before
+                // it can start any children
+                if let Some(mut procs) = procs.as_ref() {
+                    procs.write_all(b"0")?;
+                }
+                if let Some(rootfs) = rootfs.as_ref() {
+                    if libc::chroot(rootfs.as_ptr()) != 0 || libc::chdir(chroot_dir.as_ptr()) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(cmd)
+    }
+}
+
+/// Sends `signal` ("SIGTERM", "TERM" or "15") to a process through the task
+/// waiting for it. That task only reaps the process once it is done sending, so
+/// the pid can't have been reused by then. A process that already exited is
+/// left alone.
+async fn signal(
+    signals: &mpsc::UnboundedSender<SignalRequest>,
+    signal: &str,
+) -> Result<(), RuntimeError> {
+    let number = signal_number(signal)?;
+    let (reply, result) = oneshot::channel();
+    if signals.send((number, reply)).is_err() {
+        return Ok(());
+    }
+    match result.await {
+        Ok(Err(e)) => {
+            let e = format!("could not send {}: {}", signal, e);
+            Err(RuntimeError::Other(e))
+        }
+        // dropped when the process exited before it got to the signal
+        Ok(Ok(())) | Err(_) => Ok(()),
+    }
+}
+
+fn signal_number(signal: &str) -> Result<i32, RuntimeError> {
+    let name = signal.trim_start_matches("SIG");
+    let number = match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        n => n
+            .parse()
+            .map_err(|_| RuntimeError::Other(format!("unknown signal {:?}", signal)))?,
+    };
+    Ok(number)
+}
+
+fn kill(pid: u32, signal: i32) -> io::Result<()> {
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        // gone already, which is what a stop wants
+        e if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        e => Err(e),
+    }
+}
+
+fn oom_killed(cgroup: &Path) -> bool {
+    let Ok(events) = fs::read_to_string(cgroup.join("memory.events")) else {
+        return false;
+    };
+    events
+        .lines()
+        .filter_map(|l| l.strip_prefix("oom_kill "))
+        .any(|n| n.trim() != "0")
+}
+
+/// The value of `key` in a flat keyed cgroup file, like `usage_usec` in
+/// cpu.stat.
+fn cgroup_value(cgroup: &Path, file: &str, key: &str) -> Option<u64> {
+    let content = fs::read_to_string(cgroup.join(file)).ok()?;
+    let line = content
+        .lines()
+        .find_map(|l| l.strip_prefix(key)?.strip_prefix(' '))?;
+    line.trim().parse().ok()
+}
+
+/// A cgroup file holding a single number, "max" for no limit.
+fn cgroup_number(cgroup: &Path, file: &str) -> u64 {
+    let content = fs::read_to_string(cgroup.join(file)).unwrap_or_default();
+    content.trim().parse().unwrap_or(0)
+}
+
+/// Bytes read and written by the cgroup, over all devices in io.stat.
+fn cgroup_io(cgroup: &Path) -> (u64, u64) {
+    let content = fs::read_to_string(cgroup.join("io.stat")).unwrap_or_default();
+    let fields = content.lines().flat_map(|l| l.split_whitespace().skip(1));
+    fields.fold((0, 0), |(read, written), field| {
+        let bytes = |prefix| field.strip_prefix(prefix)?.parse::<u64>().ok();
+        match (bytes("rbytes="), bytes("wbytes=")) {
+            (Some(n), _) => (read + n, written),
+            (_, Some(n)) => (read, written + n),
+            _ => (read, written),
+        }
+    })
+}
+
+fn not_found(id: &str) -> RuntimeError {
+    RuntimeError::NotFound(format!("no such process: {}", id))
+}
+
+fn unsupported(what: &str) -> RuntimeError {
+    RuntimeError::Other(format!("{} is not supported by the process driver", what))
+}
+
+#[async_trait]
+impl ContainerRuntime for ProcessRuntime {
+    /// Unpacks the rootfs tarball named by the image, once. Host processes
+    /// have nothing to pull.
+    async fn pull(&self, config: &Config, _progress: &PullProgressFn) -> Result<(), RuntimeError> {
+        let Some(rootfs) = self.rootfs(&config.image) else {
+            return Ok(());
+        };
+        if rootfs.exists() {
+            return Ok(());
+        }
+
+        let err = |e: String| RuntimeError::ImagePull(format!("{}: {}", config.image, e));
+        let unpacking = rootfs.with_extension("unpacking");
+        let _ = fs::remove_dir_all(&unpacking);
+        fs::create_dir_all(&unpacking).map_err(|e| err(e.to_string()))?;
+        let status = Command::new("tar")
+            .arg("-xf")
+            .arg(&config.image)
+            .arg("-C")
+            .arg(&unpacking)
+            .status()
+            .await
+            .map_err(|e| err(e.to_string()))?;
+        if !status.success() {
+            let _ = fs::remove_dir_all(&unpacking);
+            return Err(err(format!("tar exited with {}", status)));
+        }
+        fs::rename(&unpacking, &rootfs).map_err(|e| err(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn run(&self, config: &Config) -> Result<ContainerHandle, RuntimeError> {
+        if config.entrypoint.is_empty() && config.cmd.is_empty() {
+            let e = "process tasks need an entrypoint or a cmd".to_string();
+            return Err(RuntimeError::Create(e));
+        }
+        if !config.volumes.is_empty() {
+            return Err(RuntimeError::Create(unsupported("volumes").to_string()));
+        }
+        {
+            let procs = self.procs.lock().unwrap();
+            if procs.values().any(|p| p.name == config.name) {
+                let e = format!("process name {:?} is already in use", config.name);
+                return Err(RuntimeError::CreateConflict(e));
+            }
+        }
+
+        let id = Uuid::new_v4().simple().to_string();
+        let cgroup = match self.create_cgroup(&id, config) {
+            Ok(cgroup) => Some(cgroup),
+            Err(e) if config.cpu > 0.0 || config.memory > 0 => {
+                let e = format!("could not apply limits in cgroup {}: {}", id, e);
+                return Err(RuntimeError::Create(e));
+            }
+            Err(e) => {
+                error!("[WORKER] Running process {} without a cgroup: {}", id, e);
+                None
+            }
+        };
+
+        let spawned = self
+            .command(config, cgroup.as_deref())
+            .and_then(|mut cmd| cmd.spawn());
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                if let Some(cgroup) = cgroup.as_ref() {
+                    let _ = fs::remove_dir(cgroup);
+                }
+                return Err(RuntimeError::Start(e.to_string()));
+            }
+        };
+
+        let (exited_tx, exited) = watch::channel(false);
+        let (signals, mut signal_requests) = mpsc::unbounded_channel::<SignalRequest>();
+        let pid = child.id().unwrap_or_default();
+        let output = Arc::new(Mutex::new(Output::new()));
+        let proc = Proc {
+            name: config.name.clone(),
+            labels: config.labels.clone(),
+            signals,
+            running: true,
+            exit_code: None,
+            finished_at: None,
+            cgroup,
+            output: output.clone(),
+            exited,
+            last_cpu: None,
+        };
+        self.procs.lock().unwrap().insert(id.clone(), proc);
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let stdout = output::collect(output.clone(), LogSource::Stdout, stdout);
+        let stderr = output::collect(output.clone(), LogSource::Stderr, stderr);
+        let readers = futures::future::join(tokio::spawn(stdout), tokio::spawn(stderr));
+        let procs = self.procs.clone();
+        let waited_id = id.clone();
+        tokio::spawn(async move {
+            let status = loop {
+                tokio::select! {
+                    status = child.wait() => break status,
+                    Some((signal, reply)) = signal_requests.recv() => {
+                        let _ = reply.send(kill(pid, signal));
+                    }
+                }
+            };
+            // a child the process left behind can keep its output open
+            let _ = tokio::time::timeout(Duration::from_secs(1), readers).await;
+
+            let exit_code = match status {
+                Ok(status) => status
+                    .code()
+                    .or_else(|| status.signal().map(|s| 128 + s))
+                    .map(i64::from),
+                Err(_) => None,
+            };
+            if let Some(p) = procs.lock().unwrap().get_mut(&waited_id) {
+                p.running = false;
+                p.exit_code = exit_code;
+                p.finished_at = Some(Utc::now());
+            }
+            output.lock().unwrap().close();
+            let _ = exited_tx.send(true);
+        });
+
+        info!("[WORKER] Process {} started: {:?}", id, config.entrypoint);
+        Ok(ContainerHandle { id })
+    }
+
+    async fn stop(&self, id: &str, options: &StopOptions) -> Result<StopOutcome, RuntimeError> {
+        let (signals, cgroup, mut exited) = {
+            let procs = self.procs.lock().unwrap();
+            let p = procs.get(id).ok_or_else(|| not_found(id))?;
+            if !p.running {
+                return Ok(StopOutcome::Exited);
+            }
+            (p.signals.clone(), p.cgroup.clone(), p.exited.clone())
+        };
+
+        signal(&signals, &options.signal).await?;
+        let wait = exited.wait_for(|exited| *exited);
+        if tokio::time::timeout(options.grace, wait).await.is_ok() {
+            return Ok(StopOutcome::Exited);
+        }
+
+        info!(
+            "[WORKER] Process {} still running after {:?}, killing it",
+            id, options.grace
+        );
+        // takes whatever the process started down with it
+        if let Some(cgroup) = cgroup {
+            let _ = fs::write(cgroup.join("cgroup.kill"), "1");
+        }
+        if let Err(e) = signal(&signals, "KILL").await {
+            error!("[WORKER] Error killing process {}: {}", id, e);
+        }
+        let _ = exited.wait_for(|exited| *exited).await;
+        Ok(StopOutcome::Killed)
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), RuntimeError> {
+        let mut procs = self.procs.lock().unwrap();
+        let p = procs.get(id).ok_or_else(|| not_found(id))?;
+        if p.running {
+            return Err(RuntimeError::InUse(format!("process {} is running", id)));
+        }
+        if let Some(cgroup) = p.cgroup.as_ref() {
+            if let Err(e) = fs::remove_dir(cgroup) {
+                error!("[WORKER] Error removing cgroup {:?}: {}", cgroup, e);
+            }
+        }
+        procs.remove(id);
+        Ok(())
+    }
+
+    async fn inspect(&self, id: &str) -> Result<ContainerInfo, RuntimeError> {
+        let procs = self.procs.lock().unwrap();
+        let p = procs.get(id).ok_or_else(|| not_found(id))?;
+        Ok(ContainerInfo {
+            id: id.to_string(),
+            running: p.running,
+            exit_code: p.exit_code,
+            oom_killed: p.cgroup.as_deref().is_some_and(oom_killed),
+            finished_at: p.finished_at,
+            ports: HashMap::new(),
+        })
+    }
+
+    /// Only knows what this runtime started, its processes aren't found again
+    /// after the worker restarts.
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>, RuntimeError> {
+        let procs = self.procs.lock().unwrap();
+        let found = procs
+            .iter()
+            .filter(|(_, p)| p.labels.contains_key(label))
+            .map(|(id, p)| ContainerSummary {
+                id: id.clone(),
+                name: p.name.clone(),
+                running: p.running,
+                labels: p.labels.clone(),
+            });
+        Ok(found.collect())
+    }
+
+    /// From the process's cgroup. Processes use the host's network, theirs
+    /// can't be told apart and isn't counted.
+    async fn stats(&self, id: &str) -> Result<ContainerStats, RuntimeError> {
+        let mut procs = self.procs.lock().unwrap();
+        let p = procs.get_mut(id).ok_or_else(|| not_found(id))?;
+        let Some(cgroup) = p.cgroup.as_deref() else {
+            return Ok(ContainerStats {
+                sampled_at: Utc::now(),
+                ..Default::default()
+            });
+        };
+
+        let usage_usec = cgroup_value(cgroup, "cpu.stat", "usage_usec").unwrap_or(0);
+        let cpu_time = Duration::from_micros(usage_usec);
+        let (block_read_bytes, block_write_bytes) = cgroup_io(cgroup);
+        let stats = ContainerStats {
+            cpu_percent: cpu_percent(p.last_cpu, cpu_time),
+            memory_bytes: cgroup_number(cgroup, "memory.current"),
+            memory_limit_bytes: cgroup_number(cgroup, "memory.max"),
+            block_read_bytes,
+            block_write_bytes,
+            sampled_at: Utc::now(),
+            ..Default::default()
+        };
+        p.last_cpu = Some((cpu_time, stats.sampled_at));
+        Ok(stats)
+    }
+
+    async fn logs(&self, id: &str, options: &LogOptions) -> Result<LogStream, RuntimeError> {
+        let procs = self.procs.lock().unwrap();
+        let p = procs.get(id).ok_or_else(|| not_found(id))?;
+        let logs = p.output.lock().unwrap().stream(options);
+        Ok(logs)
+    }
+
+    async fn exec(&self, _id: &str, _options: &ExecOptions) -> Result<ExecSession, RuntimeError> {
+        Err(unsupported("exec"))
+    }
+
+    async fn exec_exit_code(&self, _exec_id: &str) -> Result<Option<i64>, RuntimeError> {
+        Err(unsupported("exec"))
+    }
+
+    async fn resize_exec(
+        &self,
+        _exec_id: &str,
+        _rows: u16,
+        _cols: u16,
+    ) -> Result<(), RuntimeError> {
+        Err(unsupported("exec"))
+    }
+
+    async fn list_volumes(&self) -> Result<Vec<VolumeInfo>, RuntimeError> {
+        Ok(vec![])
+    }
+
+    async fn create_volume(&self, _name: &str) -> Result<VolumeInfo, RuntimeError> {
+        Err(unsupported("volumes"))
+    }
+
+    async fn remove_volume(&self, _name: &str) -> Result<(), RuntimeError> {
+        Err(unsupported("volumes"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt;
+
+    fn runtime() -> (ProcessRuntime, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("cube-process-{}", Uuid::new_v4()));
+        // plain directories stand in for cgroupfs, the limits end up in files
+        let runtime = ProcessRuntime::new(dir.join("data"), dir.join("cgroup"));
+        (runtime, dir)
+    }
+
+    fn config(name: &str, script: &str) -> Config {
+        Config {
+            name: name.to_string(),
+            entrypoint: vec!["/bin/sh".to_string(), "-c".to_string()],
+            cmd: vec![script.to_string()],
+            env: vec!["GREETING=hello".to_string()],
+            ..Default::default()
+        }
+    }
+
+    async fn wait_exit(runtime: &ProcessRuntime, id: &str) -> ContainerInfo {
+        loop {
+            let info = runtime.inspect(id).await.unwrap();
+            if !info.running {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_process() {
+        let (runtime, dir) = runtime();
+        let config = Config {
+            cpu: 0.5,
+            memory: 64 * 1024 * 1024,
+            ..config("greeter", "echo $GREETING; echo oops >&2; exit 3")
+        };
+        let handle = runtime.run(&config).await.unwrap();
+        let err = runtime.run(&config).await.unwrap_err();
+        assert!(matches!(err, RuntimeError::CreateConflict(_)));
+
+        let cgroup = dir.join("cgroup").join(&handle.id);
+        let cpu = fs::read_to_string(cgroup.join("cpu.max")).unwrap();
+        assert_eq!(cpu, "50000 100000");
+        let memory = fs::read_to_string(cgroup.join("memory.max")).unwrap();
+        assert_eq!(memory, "67108864");
+
+        // the kernel would keep these up to date
+        fs::write(
+            cgroup.join("cpu.stat"),
+            "usage_usec 250000\nuser_usec 200000\n",
+        )
+        .unwrap();
+        fs::write(cgroup.join("memory.current"), "1048576\n").unwrap();
+        let io = "8:0 rbytes=4096 wbytes=512 rios=1 wios=1\n8:16 rbytes=4096 wbytes=0\n";
+        fs::write(cgroup.join("io.stat"), io).unwrap();
+        let stats = runtime.stats(&handle.id).await.unwrap();
+        assert_eq!(stats.memory_bytes, 1048576);
+        assert_eq!(stats.memory_limit_bytes, 67108864);
+        assert_eq!(
+            (stats.block_read_bytes, stats.block_write_bytes),
+            (8192, 512)
+        );
+        // there is nothing to compare the first sample with
+        assert_eq!(stats.cpu_percent, 0.0);
+
+        let info = wait_exit(&runtime, &handle.id).await;
+        assert_eq!(info.exit_code, Some(3));
+        assert!(!info.oom_killed);
+
+        let logs = runtime.logs(&handle.id, &LogOptions::default()).await;
+        let logs: Vec<_> = logs.unwrap().try_collect().await.unwrap();
+        let mut lines: Vec<_> = logs
+            .iter()
+            .map(|c| (c.source, c.message.as_str()))
+            .collect();
+        lines.sort_by_key(|(_, message)| *message);
+        assert_eq!(
+            lines,
+            [
+                (LogSource::Stdout, "hello\n"),
+                (LogSource::Stderr, "oops\n")
+            ]
+        );
+
+        // the cgroup's interface files keep the test's stand-in from being removed
+        runtime.remove(&handle.id).await.unwrap();
+        assert!(matches!(
+            runtime.inspect(&handle.id).await,
+            Err(RuntimeError::NotFound(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_process() {
+        let (runtime, dir) = runtime();
+        let sleeper = runtime
+            .run(&config("sleeper", "exec sleep 30"))
+            .await
+            .unwrap();
+        let stubborn = config(
+            "stubborn",
+            "trap '' TERM; echo ready; while true; do sleep 0.1; done",
+        );
+        let stubborn = runtime.run(&stubborn).await.unwrap();
+        let err = runtime.remove(&sleeper.id).await.unwrap_err();
+        assert!(matches!(err, RuntimeError::InUse(_)));
+
+        let options = StopOptions {
+            signal: "SIGTERM".to_string(),
+            grace: Duration::from_secs(5),
+        };
+        let outcome = runtime.stop(&sleeper.id, &options).await.unwrap();
+        assert_eq!(outcome, StopOutcome::Exited);
+        let info = runtime.inspect(&sleeper.id).await.unwrap();
+        assert_eq!(info.exit_code, Some(128 + 15));
+
+        // exits on its own while it is being stopped
+        let quick = runtime.run(&config("quick", "exit 0")).await.unwrap();
+        let outcome = runtime.stop(&quick.id, &options).await.unwrap();
+        assert_eq!(outcome, StopOutcome::Exited);
+        assert_eq!(signal_number("15"), Ok(libc::SIGTERM));
+        assert!(signal_number("SIGNOPE").is_err());
+
+        // only stop it once the trap is set
+        let follow = LogOptions {
+            follow: true,
+            ..Default::default()
+        };
+        let mut logs = runtime.logs(&stubborn.id, &follow).await.unwrap();
+        let ready = logs.try_next().await.unwrap().unwrap();
+        assert_eq!(ready.message, "ready\n");
+
+        let options = StopOptions {
+            grace: Duration::from_millis(200),
+            ..options
+        };
+        let outcome = runtime.stop(&stubborn.id, &options).await.unwrap();
+        assert_eq!(outcome, StopOutcome::Killed);
+        // the follower is done once the process is
+        assert!(logs.try_next().await.unwrap().is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
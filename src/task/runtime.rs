@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::pin::Pin;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWrite;
+
+use super::task::{Config, PortBinding};
+
+/// A container the runtime has started (or stopped).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContainerHandle {
+    pub id: String,
+}
+
+/// How to stop a container: `signal` goes first, SIGKILL once `grace` is
+/// over and the container is still running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopOptions {
+    pub signal: String,
+    pub grace: Duration,
+}
+
+impl Default for StopOptions {
+    fn default() -> Self {
+        StopOptions {
+            signal: "SIGTERM".to_string(),
+            grace: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How a container ended when it was asked to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StopOutcome {
+    // exited within the grace period, or wasn't running anymore
+    Exited,
+    // still running after the grace period, it got SIGKILL
+    Killed,
+}
+
+/// What a runtime reports back about one of its containers.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub running: bool,
+    pub exit_code: Option<i64>,
+    pub oom_killed: bool,
+    pub finished_at: Option<DateTime<Utc>>,
+    // container port ("80/tcp") -> where it ended up on the host
+    pub ports: HashMap<String, PortBinding>,
+}
+
+/// What a container uses, as sampled by `ContainerRuntime::stats`. Apart from
+/// the cpu the figures are totals since it started.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ContainerStats {
+    // since the previous sample, 100 is one core
+    pub cpu_percent: f64,
+    pub memory_bytes: u64,
+    // 0 without a limit
+    pub memory_limit_bytes: u64,
+    pub network_rx_bytes: u64,
+    pub network_tx_bytes: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+    pub sampled_at: DateTime<Utc>,
+}
+
+/// Cpu usage between two samples of a container's cpu time, for runtimes that
+/// only have the running total.
+pub fn cpu_percent(previous: Option<(Duration, DateTime<Utc>)>, cpu_time: Duration) -> f64 {
+    let Some((previous, at)) = previous else {
+        return 0.0;
+    };
+    let elapsed = (Utc::now() - at).to_std().unwrap_or_default();
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    cpu_time.saturating_sub(previous).as_secs_f64() / elapsed.as_secs_f64() * 100.0
+}
+
+/// A container as `ContainerRuntime::list` finds it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContainerSummary {
+    pub id: String,
+    pub name: String,
+    pub running: bool,
+    pub labels: HashMap<String, String>,
+}
+
+/// A named volume known to the runtime.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VolumeInfo {
+    pub name: String,
+    pub driver: String,
+    pub mountpoint: String,
+}
+
+/// How far along pulling an image is, per layer.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PullProgress {
+    pub layers: HashMap<String, LayerProgress>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LayerProgress {
+    pub status: String,
+    pub current: i64,
+    pub total: i64,
+}
+
+impl LayerProgress {
+    fn is_done(&self) -> bool {
+        matches!(self.status.as_str(), "Pull complete" | "Already exists")
+    }
+
+    // extracting reports progress of its own, the download is done by then
+    fn is_downloaded(&self) -> bool {
+        self.is_done()
+            || matches!(
+                self.status.as_str(),
+                "Verifying Checksum" | "Download complete" | "Extracting"
+            )
+    }
+
+    fn fraction(&self) -> f64 {
+        match self {
+            l if l.is_downloaded() => 1.0,
+            l if l.total > 0 => l.current as f64 / l.total as f64,
+            _ => 0.0,
+        }
+    }
+}
+
+impl PullProgress {
+    pub fn percent(&self) -> f64 {
+        if self.layers.is_empty() {
+            return 0.0;
+        }
+        let done: f64 = self.layers.values().map(|l| l.fraction()).sum();
+        (done / self.layers.len() as f64 * 100.0).round()
+    }
+}
+
+/// Which logs to read, these double as the query parameters of the logs routes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogOptions {
+    // keep the stream open and send new output as it is written
+    pub follow: bool,
+    // only the last `tail` lines
+    pub tail: Option<u64>,
+    pub since: Option<DateTime<Utc>>,
+    pub stdout: bool,
+    pub stderr: bool,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            follow: false,
+            tail: None,
+            since: None,
+            stdout: true,
+            stderr: true,
+        }
+    }
+}
+
+impl LogOptions {
+    /// Whether `chunk` is selected by the stream and `since` options.
+    pub fn matches(&self, chunk: &LogChunk) -> bool {
+        let selected = match chunk.source {
+            LogSource::Stdout => self.stdout,
+            LogSource::Stderr => self.stderr,
+        };
+        let recent = match (self.since, chunk.timestamp) {
+            (Some(since), Some(time)) => time >= since,
+            _ => true,
+        };
+        selected && recent
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogSource {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogChunk {
+    pub source: LogSource,
+    // when the container wrote it, if the runtime knows
+    pub timestamp: Option<DateTime<Utc>>,
+    pub message: String,
+}
+
+pub type LogStream = BoxStream<'static, Result<LogChunk, RuntimeError>>;
+
+/// What to run with `exec`, these double as the query parameters of the exec
+/// routes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecOptions {
+    // split on whitespace, there is no quoting
+    pub cmd: String,
+    pub tty: bool,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        ExecOptions {
+            cmd: "/bin/sh".to_string(),
+            tty: true,
+        }
+    }
+}
+
+impl ExecOptions {
+    pub fn command(&self) -> Vec<String> {
+        self.cmd.split_whitespace().map(String::from).collect()
+    }
+}
+
+/// A process started with `exec`. Writing to `input` feeds its stdin, `output`
+/// ends when it exits.
+pub struct ExecSession {
+    pub id: String,
+    pub output: BoxStream<'static, Result<Vec<u8>, RuntimeError>>,
+    pub input: Pin<Box<dyn AsyncWrite + Send>>,
+}
+
+impl Debug for ExecSession {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("ExecSession").field("id", &self.id).finish()
+    }
+}
+
+/// Callback the runtime reports pull progress to.
+pub type PullProgressFn<'a> = dyn Fn(&PullProgress) + Send + Sync + 'a;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    ImagePull(String),
+    CreateConflict(String),
+    Create(String),
+    Start(String),
+    NotFound(String),
+    InUse(String),
+    DaemonUnreachable(String),
+    Other(String),
+}
+
+impl RuntimeError {
+    /// The runtime could not be reached at all, so trying again later may work.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, RuntimeError::DaemonUnreachable(_))
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::ImagePull(e) => write!(f, "error pulling image: {}", e),
+            RuntimeError::CreateConflict(e) => write!(f, "container already exists: {}", e),
+            RuntimeError::Create(e) => write!(f, "error creating container: {}", e),
+            RuntimeError::Start(e) => write!(f, "error starting container: {}", e),
+            RuntimeError::NotFound(e) => write!(f, "not found: {}", e),
+            RuntimeError::InUse(e) => write!(f, "still in use: {}", e),
+            RuntimeError::DaemonUnreachable(e) => write!(f, "runtime unreachable: {}", e),
+            RuntimeError::Other(e) => write!(f, "runtime error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Everything the worker needs from the thing that actually runs containers.
+///
+/// `Docker` is the real implementation; `FakeRuntime` keeps everything in memory
+/// so the worker and manager can be exercised without a docker daemon.
+#[async_trait]
+pub trait ContainerRuntime: Debug + Send + Sync {
+    /// Makes sure `config.image` is available locally. `progress` is called
+    /// every time a layer makes progress.
+    async fn pull(&self, config: &Config, progress: &PullProgressFn) -> Result<(), RuntimeError>;
+    /// Creates and starts a container. The image has to be pulled already. A
+    /// container that was created but didn't start is removed again.
+    async fn run(&self, config: &Config) -> Result<ContainerHandle, RuntimeError>;
+    /// Stops the container but keeps it around, with its logs, until `remove`.
+    async fn stop(&self, id: &str, options: &StopOptions) -> Result<StopOutcome, RuntimeError>;
+    async fn remove(&self, id: &str) -> Result<(), RuntimeError>;
+    async fn inspect(&self, id: &str) -> Result<ContainerInfo, RuntimeError>;
+    /// The containers carrying `label`, running or not.
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>, RuntimeError>;
+    async fn logs(&self, id: &str, options: &LogOptions) -> Result<LogStream, RuntimeError>;
+    /// Samples what the running container uses.
+    async fn stats(&self, id: &str) -> Result<ContainerStats, RuntimeError>;
+    /// Starts another process in a running container, attached to its stdin
+    /// and output.
+    async fn exec(&self, id: &str, options: &ExecOptions) -> Result<ExecSession, RuntimeError>;
+    /// The exit code of an exec session, `None` while it is still running.
+    async fn exec_exit_code(&self, exec_id: &str) -> Result<Option<i64>, RuntimeError>;
+    /// Changes the tty size of an exec session, `exec_id` is `ExecSession::id`.
+    async fn resize_exec(&self, exec_id: &str, rows: u16, cols: u16) -> Result<(), RuntimeError>;
+
+    async fn list_volumes(&self) -> Result<Vec<VolumeInfo>, RuntimeError>;
+    async fn create_volume(&self, name: &str) -> Result<VolumeInfo, RuntimeError>;
+    /// Fails with `RuntimeError::InUse` while a container still uses the volume.
+    async fn remove_volume(&self, name: &str) -> Result<(), RuntimeError>;
+}
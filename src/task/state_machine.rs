@@ -1,17 +1,93 @@
+use std::fmt::{self, Display, Formatter};
+
 use super::task::State;
 
 type S = State;
 
 pub fn state_transition_map(state: State) -> &'static [State] {
     match state {
-        S::Pending => &[S::Scheduled],
-        S::Scheduled => &[S::Scheduled, S::Running, S::Failed],
-        S::Running => &[S::Running, S::Completed, S::Failed],
+        S::Pending => &[S::Scheduled, S::Cancelled],
+        S::Scheduled => &[
+            S::Scheduled,
+            S::Running,
+            S::Stopping,
+            S::Failed,
+            S::Cancelled,
+        ],
+        S::Running => &[
+            S::Running,
+            S::Stopping,
+            S::Restarting,
+            S::Completed,
+            S::Failed,
+        ],
+        // waiting out the backoff before running again
+        S::Restarting => &[S::Restarting, S::Running, S::Stopping, S::Failed],
+        // a task that never got to run ends up cancelled
+        S::Stopping => &[S::Stopping, S::Completed, S::Failed, S::Cancelled],
         S::Completed => &[],
         S::Failed => &[],
+        S::Cancelled => &[],
     }
 }
 
 pub fn is_valid_transition(from: State, to: State) -> bool {
     state_transition_map(from).contains(&to)
 }
+
+/// Whether `to` can be reached from `from` in any number of valid transitions.
+pub fn is_reachable(from: State, to: State) -> bool {
+    let mut seen = vec![from];
+    let mut next = vec![from];
+    while let Some(state) = next.pop() {
+        for &s in state_transition_map(state) {
+            if s == to {
+                return true;
+            }
+            if !seen.contains(&s) {
+                seen.push(s);
+                next.push(s);
+            }
+        }
+    }
+    false
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidTransition {
+    pub from: State,
+    pub to: State,
+}
+
+impl Display for InvalidTransition {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid state transition from {:?} to {:?}",
+            self.from, self.to
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        assert!(!is_valid_transition(S::Pending, S::Pending));
+        assert!(is_valid_transition(S::Pending, S::Cancelled));
+        assert!(is_valid_transition(S::Running, S::Stopping));
+        assert!(!is_valid_transition(S::Running, S::Scheduled));
+        assert!(!is_valid_transition(S::Completed, S::Running));
+        for state in [S::Completed, S::Failed, S::Cancelled] {
+            assert!(state.is_terminal());
+        }
+
+        assert!(is_reachable(S::Scheduled, S::Completed));
+        assert!(is_reachable(S::Restarting, S::Completed));
+        assert!(!is_reachable(S::Stopping, S::Running));
+        assert!(!is_reachable(S::Running, S::Pending));
+        assert!(!is_reachable(S::Completed, S::Completed));
+    }
+}
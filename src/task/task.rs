@@ -1,14 +1,33 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
 
-use bollard::container::{self, LogsOptions};
+use async_trait::async_trait;
+use bollard::auth::DockerCredentials;
+use bollard::container::{
+    self, KillContainerOptions, ListContainersOptions, LogOutput, LogsOptions, StatsOptions,
+    WaitContainerOptions,
+};
+use bollard::errors::Error::DockerResponseServerError;
+use bollard::exec::{CreateExecOptions, ResizeExecOptions, StartExecOptions, StartExecResults};
 use bollard::image::CreateImageOptions;
-use bollard::models::{CreateImageInfo, HostConfig, RestartPolicy};
+use bollard::models::{CreateImageInfo, HostConfig, Mount, MountTypeEnum, RestartPolicyNameEnum};
+use bollard::volume::{CreateVolumeOptions, ListVolumesOptions, RemoveVolumeOptions};
 use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use tracing::{error, info};
 use uuid::Uuid;
 
+use super::probe::{Health, Probe};
+use super::runtime::{
+    ContainerHandle, ContainerInfo, ContainerRuntime, ContainerStats, ContainerSummary,
+    ExecOptions, ExecSession, LogChunk, LogOptions, LogSource, LogStream, PullProgress,
+    PullProgressFn, RuntimeError, StopOptions, StopOutcome, VolumeInfo,
+};
+use super::state_machine::{is_valid_transition, state_transition_map, InvalidTransition};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Task {
@@ -16,15 +35,46 @@ pub struct Task {
     pub container_id: String,
     pub name: String,
     pub state: State,
+    // what runs the task, docker unless it says otherwise
+    pub driver: Driver,
+    // why the task got to its current state
+    pub state_reason: String,
+    // the state changes of the task, oldest first
+    pub history: Vec<Transition>,
     pub image: String,
+    pub cmd: Vec<String>,
+    pub entrypoint: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub working_dir: String,
     pub cpu: f64,
     pub memory: u64,
+    // cpu time the task may use before it is ended, only the wasm driver has it
+    pub cpu_time_secs: Option<u64>,
     pub disk: u64,
     pub exposed_ports: HashSet<Port>,
-    pub port_bindings: HashMap<String, String>,
-    pub restart_policy: String, // empty, always, unless-stopped, on-failure
+    // container port ("80/tcp") -> where to publish it on the host
+    pub port_bindings: HashMap<String, PortBinding>,
+    // the host ports docker actually assigned, filled in by the worker
+    pub host_ports: HashMap<String, PortBinding>,
+    pub volumes: Vec<VolumeMount>,
+    // name of the worker's credential for the image's registry, if it needs one
+    pub registry_credential: Option<String>,
+    pub restart_policy: RestartPolicy,
+    // how many times the worker restarted the task after its container stopped
+    pub restart_count: u32,
+    pub liveness: Option<Probe>,
+    pub readiness: Option<Probe>,
+    pub health: Health,
+    // sent to the container to stop it, SIGKILL follows after the grace period
+    pub stop_signal: String,
+    pub stop_grace_secs: u64,
+    // how the container ended the last time the worker stopped it
+    pub stop_outcome: Option<StopOutcome>,
     pub start_time: DateTime<Utc>,
     pub finish_time: Option<DateTime<Utc>>,
+    pub exit_code: Option<i64>,
+    pub failure_reason: Option<FailureReason>,
+    pub attempts: u32,
 }
 
 impl Default for Task {
@@ -34,27 +84,289 @@ impl Default for Task {
             container_id: "".to_string(),
             name: "".to_string(),
             state: State::Pending,
+            driver: Driver::Docker,
+            state_reason: "".to_string(),
+            history: vec![],
             image: "".to_string(),
+            cmd: vec![],
+            entrypoint: vec![],
+            env: HashMap::new(),
+            working_dir: "".to_string(),
             cpu: 0.0,
             memory: 0,
+            cpu_time_secs: None,
             disk: 0,
             exposed_ports: HashSet::new(),
             port_bindings: HashMap::new(),
-            restart_policy: "".to_string(),
+            host_ports: HashMap::new(),
+            volumes: vec![],
+            registry_credential: None,
+            restart_policy: RestartPolicy::Never,
+            restart_count: 0,
+            liveness: None,
+            readiness: None,
+            health: Health::default(),
+            stop_signal: "SIGTERM".to_string(),
+            stop_grace_secs: 10,
+            stop_outcome: None,
             start_time: Utc::now(),
             finish_time: None,
+            exit_code: None,
+            failure_reason: None,
+            attempts: 0,
         }
     }
 }
 
+impl Task {
+    /// The task as it was asked for, without what happened to it since and
+    /// without its `env`, which may hold secrets. It is what `TASK_SPEC_LABEL`
+    /// holds, `start_time` being when its container was started.
+    pub fn spec(&self) -> Task {
+        Task {
+            id: self.id,
+            name: self.name.clone(),
+            state: State::Scheduled,
+            driver: self.driver,
+            image: self.image.clone(),
+            cmd: self.cmd.clone(),
+            entrypoint: self.entrypoint.clone(),
+            working_dir: self.working_dir.clone(),
+            cpu: self.cpu,
+            memory: self.memory,
+            cpu_time_secs: self.cpu_time_secs,
+            disk: self.disk,
+            exposed_ports: self.exposed_ports.clone(),
+            port_bindings: self.port_bindings.clone(),
+            volumes: self.volumes.clone(),
+            registry_credential: self.registry_credential.clone(),
+            restart_policy: self.restart_policy,
+            liveness: self.liveness.clone(),
+            readiness: self.readiness.clone(),
+            stop_signal: self.stop_signal.clone(),
+            stop_grace_secs: self.stop_grace_secs,
+            start_time: self.start_time,
+            ..Default::default()
+        }
+    }
+
+    pub fn stop_options(&self) -> StopOptions {
+        StopOptions {
+            signal: self.stop_signal.clone(),
+            grace: Duration::from_secs(self.stop_grace_secs),
+        }
+    }
+
+    /// Moves the task to `to`, if the state machine allows it, and records it
+    /// in the task's history.
+    pub fn transition(
+        &mut self,
+        to: State,
+        reason: &str,
+        actor: Actor,
+    ) -> Result<(), InvalidTransition> {
+        let from = self.state;
+        if !is_valid_transition(from, to) {
+            return Err(InvalidTransition { from, to });
+        }
+        self.state = to;
+        self.state_reason = reason.to_string();
+        self.history.push(Transition {
+            from,
+            to,
+            reason: reason.to_string(),
+            actor,
+            timestamp: Utc::now(),
+        });
+        self.trim_history();
+        Ok(())
+    }
+
+    /// Adds the transitions of `history` this task doesn't have yet, like the
+    /// ones the worker recorded on its copy of the task.
+    pub fn merge_history(&mut self, history: &[Transition]) {
+        for t in history {
+            if !self.history.contains(t) {
+                self.history.push(t.clone());
+            }
+        }
+        self.history.sort_by_key(|t| t.timestamp);
+        self.trim_history();
+    }
+
+    // a task that keeps getting restarted would grow its history forever
+    fn trim_history(&mut self) {
+        let extra = self.history.len().saturating_sub(MAX_HISTORY);
+        self.history.drain(..extra);
+    }
+}
+
+/// How many transitions a task keeps, older ones are dropped.
+pub const MAX_HISTORY: usize = 256;
+
+/// Who made a task change state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Actor {
+    // a request to the manager's or the worker's api
+    Api,
+    // the worker, on its own, like when a container exits
+    Worker,
+    // the manager's loops, bringing tasks where they should be
+    Reconciler,
+}
+
+/// One state change of a task.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    pub from: State,
+    pub to: State,
+    pub reason: String,
+    pub actor: Actor,
+    pub timestamp: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum State {
     Pending,
     Scheduled,
     Running,
+    Stopping,
+    Restarting,
     Completed,
     Failed,
+    Cancelled,
+}
+
+impl State {
+    /// Nothing happens to a task anymore once it is in one of these.
+    pub fn is_terminal(&self) -> bool {
+        state_transition_map(*self).is_empty()
+    }
+}
+
+/// How a worker runs a task. With `process` the task is a plain process on
+/// the worker's host, see `ProcessRuntime`, with `wasm` a WASI module run by
+/// the worker itself, see `WasmRuntime`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    #[default]
+    Docker,
+    Process,
+    Wasm,
+}
+
+/// What the worker does when a task's container stops on its own. Docker is
+/// never asked to restart anything, restarts show up on the task instead.
+///
+/// Serialized as `"never"`, `"always"`, `"unless-stopped"` or
+/// `{"on-failure": {"max_retries": 3}}`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    #[serde(alias = "", alias = "no")]
+    Never,
+    Always,
+    OnFailure {
+        max_retries: u32,
+    },
+    // tasks stopped through the api are never restarted, so for now this is
+    // the same as `Always`
+    UnlessStopped,
+}
+
+impl RestartPolicy {
+    /// Whether a task that stopped, `failed` or not, after `restart_count`
+    /// restarts should run again.
+    pub fn should_restart(&self, failed: bool, restart_count: u32) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+            RestartPolicy::OnFailure { max_retries } => failed && restart_count < *max_retries,
+        }
+    }
+}
+
+/// Why a task ended up `Failed`. Serialized as `{"kind": "image_pull", "message": "..."}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
+pub enum FailureReason {
+    ImagePull(String),
+    Create(String),
+    Start(String),
+    OomKilled,
+    NonZeroExit,
+    ContainerLost(String),
+    // the liveness probe failed too many times in a row
+    Unhealthy(String),
+    // the worker it was sent to refused it
+    Rejected(String),
+    Runtime(String),
+}
+
+// what docker says about a layer while pulling it, in order
+const LAYER_STATUSES: [&str; 8] = [
+    "Pulling fs layer",
+    "Waiting",
+    "Downloading",
+    "Verifying Checksum",
+    "Download complete",
+    "Extracting",
+    "Pull complete",
+    "Already exists",
+];
+
+impl PullProgress {
+    /// Folds one message of docker's pull stream into the progress. Returns
+    /// false for messages that are not about a layer, like the first one,
+    /// "Pulling from ...", which has the tag as its `id`.
+    fn update(&mut self, info: &CreateImageInfo) -> bool {
+        let (Some(id), Some(status)) = (info.id.as_ref(), info.status.as_ref()) else {
+            return false;
+        };
+        if !LAYER_STATUSES.contains(&status.as_str()) {
+            return false;
+        }
+        let layer = self.layers.entry(id.clone()).or_default();
+        layer.status = status.clone();
+        // only the download's bytes count, extracting reports its own
+        if status == "Downloading" {
+            if let Some(detail) = info.progress_detail.as_ref() {
+                layer.current = detail.current.unwrap_or(layer.current);
+                layer.total = detail.total.unwrap_or(layer.total);
+            }
+        }
+        true
+    }
+}
+
+impl From<bollard::models::Volume> for VolumeInfo {
+    fn from(v: bollard::models::Volume) -> Self {
+        VolumeInfo {
+            name: v.name,
+            driver: v.driver,
+            mountpoint: v.mountpoint,
+        }
+    }
+}
+
+impl From<&RuntimeError> for FailureReason {
+    fn from(e: &RuntimeError) -> Self {
+        match e {
+            RuntimeError::ImagePull(e) => FailureReason::ImagePull(e.clone()),
+            RuntimeError::CreateConflict(e) | RuntimeError::Create(e) => {
+                FailureReason::Create(e.clone())
+            }
+            RuntimeError::Start(e) => FailureReason::Start(e.clone()),
+            RuntimeError::NotFound(e) => FailureReason::ContainerLost(e.clone()),
+            RuntimeError::InUse(_)
+            | RuntimeError::DaemonUnreachable(_)
+            | RuntimeError::Other(_) => FailureReason::Runtime(e.to_string()),
+        }
+    }
 }
 
 // for now, defining my own port struct
@@ -72,6 +384,90 @@ impl Port {
     }
 }
 
+/// Parses the docker representation, "80/tcp". The protocol defaults to tcp.
+impl FromStr for Port {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, protocol) = s.split_once('/').unwrap_or((s, "tcp"));
+        let number = number
+            .parse()
+            .map_err(|_| format!("invalid port {:?}", s))?;
+        let protocol = match protocol {
+            "tcp" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => return Err(format!("invalid protocol in port {:?}", s)),
+        };
+        Ok(Port { number, protocol })
+    }
+}
+
+/// Where a container port is published on the host. An empty `host_ip` means
+/// all interfaces and a `host_port` of 0 lets docker pick a free port.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PortBinding {
+    pub host_ip: String,
+    pub host_port: u16,
+}
+
+impl PortBinding {
+    fn to_docker_repr(&self) -> bollard::models::PortBinding {
+        bollard::models::PortBinding {
+            host_ip: Some(self.host_ip.clone()).filter(|ip| !ip.is_empty()),
+            host_port: Some(self.host_port)
+                .filter(|p| *p != 0)
+                .map(|p| p.to_string()),
+        }
+    }
+
+    fn from_docker_repr(binding: &bollard::models::PortBinding) -> Self {
+        PortBinding {
+            host_ip: binding.host_ip.clone().unwrap_or_default(),
+            host_port: binding
+                .host_port
+                .as_ref()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Storage attached to a task. For a bind mount `source` is a path on the
+/// host, for a volume it is the name of a named volume, which docker creates
+/// on first use and which outlives the task.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VolumeMount {
+    pub kind: MountKind,
+    pub source: String,
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountKind {
+    Bind,
+    Volume,
+}
+
+impl VolumeMount {
+    fn to_docker_repr(&self) -> Mount {
+        let typ = match self.kind {
+            MountKind::Bind => MountTypeEnum::BIND,
+            MountKind::Volume => MountTypeEnum::VOLUME,
+        };
+        Mount {
+            target: Some(self.target.clone()),
+            source: Some(self.source.clone()),
+            typ: Some(typ),
+            read_only: Some(self.read_only),
+            ..Mount::default()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Hash, Eq, Serialize, Deserialize)]
 pub enum Protocol {
     Tcp,
@@ -114,257 +510,608 @@ pub struct Config {
     pub attach_stdout: bool,
     pub attach_stderr: bool,
     pub exposed_ports: HashSet<Port>,
+    pub port_bindings: HashMap<String, PortBinding>,
+    pub volumes: Vec<VolumeMount>,
     pub cmd: Vec<String>,
+    pub entrypoint: Vec<String>,
     pub image: String,
     pub cpu: f64,
     pub memory: i64,
+    pub cpu_time: Option<Duration>,
     pub disk: u64,
-    pub env: Vec<String>,       // maybe use a pair?
-    pub restart_policy: String, // empty, always, unless-stopped, on-failure
+    pub env: Vec<String>, // KEY=value, the way docker wants it
+    pub working_dir: String,
+    pub labels: HashMap<String, String>,
+    // filled in by the worker, tasks only know the credential's name
+    pub registry_auth: Option<RegistryAuth>,
 }
 
+/// Credentials for a private registry. Never serialized, and the secrets are
+/// left out of the `Debug` output so they don't end up in logs.
+#[derive(Clone, PartialEq, Deserialize)]
+pub struct RegistryAuth {
+    pub name: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub identity_token: Option<String>,
+}
+
+impl fmt::Debug for RegistryAuth {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("RegistryAuth")
+            .field("name", &self.name)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field(
+                "identity_token",
+                &self.identity_token.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl RegistryAuth {
+    fn to_docker_repr(&self, server: &str) -> DockerCredentials {
+        DockerCredentials {
+            username: Some(self.username.clone()).filter(|u| !u.is_empty()),
+            password: Some(self.password.clone()).filter(|p| !p.is_empty()),
+            identitytoken: self.identity_token.clone(),
+            serveraddress: Some(server.to_string()),
+            ..DockerCredentials::default()
+        }
+    }
+}
+
+/// Splits an image reference into repository and tag, "localhost:5000/app:1.0"
+/// becomes ("localhost:5000/app", "1.0"). The tag is empty if there is none.
+pub fn split_image(image: &str) -> (&str, &str) {
+    match image.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => (repo, tag),
+        _ => (image, ""),
+    }
+}
+
+/// The registry an image is pulled from. Images without one come from docker hub.
+pub fn registry_host(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => "docker.io",
+    }
+}
+
+/// Label holding the id of the task a container runs.
+pub const TASK_ID_LABEL: &str = "cube.task.id";
+/// Label holding the spec of the task a container runs (see `Task::spec`), as
+/// json, so a restarted worker can find its tasks again.
+pub const TASK_SPEC_LABEL: &str = "cube.task.spec";
+
 pub fn new_config(t: &Task) -> Config {
     Config {
         name: t.name.clone(),
         exposed_ports: t.exposed_ports.clone(),
+        port_bindings: t.port_bindings.clone(),
+        volumes: t.volumes.clone(),
+        cmd: t.cmd.clone(),
+        entrypoint: t.entrypoint.clone(),
         image: t.image.clone(),
         cpu: t.cpu,
         memory: t.memory as i64,
+        cpu_time: t.cpu_time_secs.map(Duration::from_secs),
         disk: t.disk,
-        restart_policy: t.restart_policy.clone(),
+        env: t.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect(),
+        working_dir: t.working_dir.clone(),
+        labels: HashMap::from([
+            (TASK_ID_LABEL.to_string(), t.id.to_string()),
+            (
+                TASK_SPEC_LABEL.to_string(),
+                serde_json::to_string(&t.spec()).unwrap_or_default(),
+            ),
+        ]),
         ..Default::default()
     }
 }
 
+#[derive(Debug)]
 pub struct Docker {
     pub client: bollard::Docker,
-    pub config: Config,
 }
 
-pub fn new_docker(config: Config) -> Docker {
-    let client = bollard::Docker::connect_with_local_defaults().unwrap();
-    Docker { client, config }
+/// Connects to the local docker daemon. Fails if its socket or the settings in
+/// `DOCKER_HOST` are unusable, not when the daemon is merely down.
+pub fn new_docker() -> Result<Docker, RuntimeError> {
+    let client = bollard::Docker::connect_with_local_defaults()
+        .map_err(|e| runtime_error(e, RuntimeError::DaemonUnreachable))?;
+    Ok(Docker { client })
 }
 
 impl Docker {
-    pub async fn run(&self) -> DockerResult {
-        println!("running container: {:?}", self.config);
-        let image = self.config.image.split(":").collect::<Vec<_>>();
-        let (image, tag) = (image[0], image.get(1).unwrap_or(&""));
+    /// Sends `signal` to the container. Returns false if it wasn't running.
+    async fn kill(&self, id: &str, signal: &str) -> Result<bool, RuntimeError> {
+        let options = KillContainerOptions { signal };
+        match self.client.kill_container(id, Some(options)).await {
+            Ok(()) => Ok(true),
+            Err(DockerResponseServerError {
+                status_code: 409, ..
+            }) => Ok(false),
+            Err(e) => Err(runtime_error(e, RuntimeError::Other)),
+        }
+    }
+
+    /// Waits for the container to exit, for at most `timeout`. Returns false
+    /// if it is still running.
+    async fn wait_exit(&self, id: &str, timeout: Option<Duration>) -> bool {
+        let options = WaitContainerOptions {
+            condition: "not-running",
+        };
+        let mut wait = self.client.wait_container(id, Some(options));
+        // a non zero exit code comes out as an error, the container exited all the same
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, wait.next()).await.is_ok(),
+            None => {
+                wait.next().await;
+                true
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for Docker {
+    async fn pull(&self, config: &Config, progress: &PullProgressFn) -> Result<(), RuntimeError> {
+        info!("[WORKER] Pulling image {}", config.image);
+        let (image, tag) = split_image(&config.image);
         let options = CreateImageOptions {
             from_image: image,
             tag,
             ..Default::default()
         };
         let root_fs = None;
-        let credentials = None;
+        let server = registry_host(&config.image);
+        let credentials = config
+            .registry_auth
+            .as_ref()
+            .map(|auth| auth.to_docker_repr(server));
         let mut res = self
             .client
             .create_image(Some(options), root_fs, credentials);
 
-        // un stream
-        let single_result: Option<Result<CreateImageInfo, bollard::errors::Error>> =
-            res.next().await;
-        let single_result = single_result.map_or_else(
-            || {
-                Err(bollard::errors::Error::DockerResponseServerError {
-                    status_code: 500,
-                    message: "No result".to_string(),
-                })
-            },
-            |x| x,
-        );
-
-        let single_result = match single_result {
-            Ok(x) => x,
-            Err(e) => {
-                return DockerResult {
-                    error: Some(e.to_string()),
-                    action: "run".to_string(),
-                    container_id: "".to_string(),
-                    result: "".to_string(),
-                };
+        // the pull is only done once the stream ends, and errors can show up
+        // at any point in it
+        let mut pull = PullProgress::default();
+        while let Some(info) = res.next().await {
+            let info = info.map_err(|e| runtime_error(e, RuntimeError::ImagePull))?;
+            if let Some(err) = info.error {
+                return Err(RuntimeError::ImagePull(err));
             }
-        };
+            if pull.update(&info) {
+                progress(&pull);
+            }
+        }
 
-        println!("{:?}", single_result);
-        // TODO: do something with the result
-        //
+        Ok(())
+    }
 
-        let restart_policy_name = self
-            .config
-            .restart_policy
-            .parse()
-            .expect("Invalid restart policy");
+    async fn run(&self, config: &Config) -> Result<ContainerHandle, RuntimeError> {
+        info!(
+            "[WORKER] Running container {} from {}",
+            config.name, config.image
+        );
 
-        let rp = RestartPolicy {
-            name: Some(restart_policy_name),
-            ..RestartPolicy::default()
+        // the worker restarts tasks itself, see `RestartPolicy`
+        let rp = bollard::models::RestartPolicy {
+            name: Some(RestartPolicyNameEnum::NO),
+            ..Default::default()
         };
 
+        let port_bindings = config
+            .port_bindings
+            .iter()
+            .map(|(port, b)| (port.clone(), Some(vec![b.to_docker_repr()])))
+            .collect();
+
         let r = HostConfig {
-            memory: Some(self.config.memory),
-            nano_cpus: Some((self.config.cpu * 1_000_000_000.0) as i64),
+            memory: Some(config.memory),
+            nano_cpus: Some((config.cpu * 1_000_000_000.0) as i64),
             restart_policy: Some(rp),
-            publish_all_ports: Some(true),
+            port_bindings: Some(port_bindings),
+            mounts: Some(config.volumes.iter().map(|v| v.to_docker_repr()).collect()),
+            // without explicit bindings keep publishing every exposed port
+            publish_all_ports: Some(config.port_bindings.is_empty()),
             ..HostConfig::default()
         };
 
+        // a bound port has to be exposed as well
+        let exposed_ports = config
+            .exposed_ports
+            .iter()
+            .map(|x| x.to_docker_repr())
+            .chain(
+                config
+                    .port_bindings
+                    .keys()
+                    .map(|p| (p.clone(), HashMap::new())),
+            )
+            .collect();
+
         let cc = container::Config {
-            image: Some(self.config.image.clone()),
+            image: Some(config.image.clone()),
             tty: Some(false),
-            env: Some(self.config.env.clone()),
-            exposed_ports: Some(
-                self.config
-                    .exposed_ports
-                    .iter()
-                    .map(|x| x.to_docker_repr())
-                    .collect::<_>(),
-            ),
+            // empty means "use whatever the image says"
+            cmd: Some(config.cmd.clone()).filter(|c| !c.is_empty()),
+            entrypoint: Some(config.entrypoint.clone()).filter(|e| !e.is_empty()),
+            working_dir: Some(config.working_dir.clone()).filter(|w| !w.is_empty()),
+            env: Some(config.env.clone()),
+            labels: Some(config.labels.clone()),
+            exposed_ports: Some(exposed_ports),
             host_config: Some(r),
             ..container::Config::default()
         };
 
-        // TODO: create container
         let options = container::CreateContainerOptions {
-            name: self.config.name.clone(),
+            name: config.name.clone(),
             //TODO: maybe add a platform
             platform: None,
         };
 
-        let res = self.client.create_container(Some(options), cc).await;
-        let res = match res {
-            Ok(x) => x,
-            Err(e) => {
-                return DockerResult {
-                    error: Some(e.to_string()),
-                    action: "run".to_string(),
-                    container_id: "".to_string(),
-                    result: "".to_string(),
-                };
-            }
-        };
+        let res = self
+            .client
+            .create_container(Some(options), cc)
+            .await
+            .map_err(|e| match e {
+                DockerResponseServerError {
+                    status_code: 409,
+                    message,
+                } => RuntimeError::CreateConflict(message),
+                e => runtime_error(e, RuntimeError::Create),
+            })?;
 
-        println!("container create res: {:?}", res);
+        info!("[WORKER] Created container {} ({})", res.id, config.name);
 
-        let err = self.client.start_container::<String>(&res.id, None).await;
-        if let Err(e) = err {
-            return DockerResult {
-                error: Some(e.to_string()),
-                action: "run".to_string(),
-                container_id: "".to_string(),
-                result: "".to_string(),
+        if let Err(e) = self.client.start_container::<String>(&res.id, None).await {
+            // it has the task's name, left behind the task could never be
+            // created again
+            let options = container::RemoveContainerOptions {
+                v: true,
+                link: false,
+                force: true,
             };
+            if let Err(e) = self.client.remove_container(&res.id, Some(options)).await {
+                error!("[WORKER] Error removing container {}: {}", res.id, e);
+            }
+            return Err(runtime_error(e, RuntimeError::Start));
         }
 
-        let container_id = res.id.clone();
-
-        let options = LogsOptions::<String> {
-            stdout: true,
-            stderr: true,
-            ..LogsOptions::default()
-        };
-        let mut res = self.client.logs(&res.id, Some(options));
-
-        let mut count = 0;
-        while let Some(res) = res.next().await {
-            let res = match res {
-                Ok(x) => x,
-                Err(e) => {
-                    return DockerResult {
-                        error: Some(e.to_string()),
-                        action: "run".to_string(),
-                        container_id: "".to_string(),
-                        result: "".to_string(),
-                    };
-                }
-            };
+        Ok(ContainerHandle { id: res.id })
+    }
 
-            println!("logs: {:?}", res);
-            count += 1;
-            if count > 10 {
-                break;
-            }
+    // docker's own stop only takes a timeout, the signal is fixed when the
+    // container is created. Sending it ourselves lets each stop pick it.
+    async fn stop(&self, id: &str, options: &StopOptions) -> Result<StopOutcome, RuntimeError> {
+        info!("[WORKER] Stopping container {} with {}", id, options.signal);
+        if !self.inspect(id).await?.running {
+            return Ok(StopOutcome::Exited);
         }
 
-        return DockerResult {
-            error: None,
-            action: "start".to_string(),
-            container_id,
-            result: "success".to_string(),
-        };
-    }
-
-    pub async fn stop(&self, id: &str) -> DockerResult {
-        println!("stopping container: {}", id);
-        let res = self.client.stop_container(&id, None).await;
-        let res = match res {
-            Ok(x) => x,
-            Err(e) => {
-                return DockerResult {
-                    error: Some(e.to_string()),
-                    action: "stop".to_string(),
-                    container_id: id.to_string(),
-                    result: "".to_string(),
-                };
-            }
-        };
+        if !self.kill(id, &options.signal).await? {
+            return Ok(StopOutcome::Exited);
+        }
+        if self.wait_exit(id, Some(options.grace)).await {
+            info!("[WORKER] Container {} stopped", id);
+            return Ok(StopOutcome::Exited);
+        }
 
-        println!("container stop res: {:?}", res);
+        info!(
+            "[WORKER] Container {} still running after {:?}, killing it",
+            id, options.grace
+        );
+        if self.kill(id, "SIGKILL").await? {
+            self.wait_exit(id, None).await;
+        }
+        Ok(StopOutcome::Killed)
+    }
 
+    async fn remove(&self, id: &str) -> Result<(), RuntimeError> {
+        // only removes anonymous volumes, named ones are kept for the next task
         let options = container::RemoveContainerOptions {
             v: true,
             link: false,
             force: false,
         };
-        let res = self.client.remove_container(&id, Some(options)).await;
-        if let Err(e) = res {
-            return DockerResult {
-                error: Some(e.to_string()),
-                action: "stop".to_string(),
-                container_id: id.to_string(),
-                result: "".to_string(),
+        self.client
+            .remove_container(id, Some(options))
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+
+        info!("[WORKER] Container {} removed", id);
+        Ok(())
+    }
+
+    async fn inspect(&self, id: &str) -> Result<ContainerInfo, RuntimeError> {
+        let res = self
+            .client
+            .inspect_container(id, None)
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+        let state = res.state.unwrap_or_default();
+        // docker reports "0001-01-01T00:00:00Z" for containers that have not finished
+        let finished_at = state
+            .finished_at
+            .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+            .map(|t| t.with_timezone(&Utc))
+            .filter(|t| t.timestamp() > 0);
+        // docker lists each port once per address family, the first one is enough
+        let ports = res
+            .network_settings
+            .and_then(|n| n.ports)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(port, bindings)| {
+                let binding = bindings?.first().map(PortBinding::from_docker_repr)?;
+                Some((port, binding))
+            })
+            .collect();
+        Ok(ContainerInfo {
+            id: res.id.unwrap_or_else(|| id.to_string()),
+            running: state.running.unwrap_or(false),
+            exit_code: state.exit_code,
+            oom_killed: state.oom_killed.unwrap_or(false),
+            finished_at,
+            ports,
+        })
+    }
+
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>, RuntimeError> {
+        let options = ListContainersOptions {
+            all: true,
+            filters: HashMap::from([("label", vec![label])]),
+            ..Default::default()
+        };
+        let res = self
+            .client
+            .list_containers(Some(options))
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+        let containers = res.into_iter().map(|c| ContainerSummary {
+            id: c.id.unwrap_or_default(),
+            // docker puts a slash in front of names
+            name: c
+                .names
+                .and_then(|n| n.into_iter().next())
+                .map(|n| n.trim_start_matches('/').to_string())
+                .unwrap_or_default(),
+            running: c.state.as_deref() == Some("running"),
+            labels: c.labels.unwrap_or_default(),
+        });
+        Ok(containers.collect())
+    }
+
+    async fn stats(&self, id: &str) -> Result<ContainerStats, RuntimeError> {
+        // without one_shot docker waits for a second sample, which the cpu
+        // usage is computed against
+        let options = StatsOptions {
+            stream: false,
+            one_shot: false,
+        };
+        let s = match self.client.stats(id, Some(options)).next().await {
+            Some(s) => s.map_err(|e| runtime_error(e, RuntimeError::Other))?,
+            None => return Err(RuntimeError::Other(format!("no stats for {}", id))),
+        };
+
+        let cpu = &s.cpu_stats;
+        let cpu_delta = cpu
+            .cpu_usage
+            .total_usage
+            .saturating_sub(s.precpu_stats.cpu_usage.total_usage);
+        let system_delta = cpu
+            .system_cpu_usage
+            .unwrap_or(0)
+            .saturating_sub(s.precpu_stats.system_cpu_usage.unwrap_or(0));
+        let cpu_percent = match system_delta {
+            0 => 0.0,
+            _ => {
+                let cpus = cpu.online_cpus.unwrap_or(1) as f64;
+                cpu_delta as f64 / system_delta as f64 * cpus * 100.0
+            }
+        };
+
+        let networks = s.networks.unwrap_or_default();
+        let block = s.blkio_stats.io_service_bytes_recursive.unwrap_or_default();
+        // "read" with cgroup v2, "Read" with v1
+        let block_bytes = |op: &str| -> u64 {
+            let entries = block.iter().filter(|e| e.op.eq_ignore_ascii_case(op));
+            entries.map(|e| e.value).sum()
+        };
+        Ok(ContainerStats {
+            cpu_percent,
+            memory_bytes: s.memory_stats.usage.unwrap_or(0),
+            memory_limit_bytes: s.memory_stats.limit.unwrap_or(0),
+            network_rx_bytes: networks.values().map(|n| n.rx_bytes).sum(),
+            network_tx_bytes: networks.values().map(|n| n.tx_bytes).sum(),
+            block_read_bytes: block_bytes("read"),
+            block_write_bytes: block_bytes("write"),
+            sampled_at: Utc::now(),
+        })
+    }
+
+    async fn logs(&self, id: &str, options: &LogOptions) -> Result<LogStream, RuntimeError> {
+        // errors only show up once the stream is read, check the container
+        // exists so a missing one is reported up front
+        self.client
+            .inspect_container(id, None)
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+
+        let options = LogsOptions::<String> {
+            follow: options.follow,
+            stdout: options.stdout,
+            stderr: options.stderr,
+            since: options.since.map_or(0, |t| t.timestamp()),
+            tail: options.tail.map_or("all".to_string(), |n| n.to_string()),
+            timestamps: true,
+            ..LogsOptions::default()
+        };
+        let logs = self.client.logs(id, Some(options)).filter_map(|log| async {
+            let (source, message) = match log {
+                Ok(LogOutput::StdOut { message }) => (LogSource::Stdout, message),
+                Ok(LogOutput::StdErr { message }) => (LogSource::Stderr, message),
+                // containers started with a tty don't separate the two
+                Ok(LogOutput::Console { message }) => (LogSource::Stdout, message),
+                Ok(LogOutput::StdIn { .. }) => return None,
+                Err(e) => return Some(Err(runtime_error(e, RuntimeError::Other))),
             };
+            let (timestamp, message) = split_timestamp(&String::from_utf8_lossy(&message));
+            Some(Ok(LogChunk {
+                source,
+                timestamp,
+                message,
+            }))
+        });
+        Ok(logs.boxed())
+    }
+
+    async fn exec(&self, id: &str, options: &ExecOptions) -> Result<ExecSession, RuntimeError> {
+        let config = CreateExecOptions {
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(options.tty),
+            cmd: Some(options.command()),
+            ..Default::default()
+        };
+        let exec = self
+            .client
+            .create_exec(id, config)
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+
+        let start = StartExecOptions {
+            detach: false,
+            tty: options.tty,
+            output_capacity: None,
+        };
+        let res = self
+            .client
+            .start_exec(&exec.id, Some(start))
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+        match res {
+            StartExecResults::Attached { output, input } => {
+                let output = output.map(|o| {
+                    o.map(|o| o.into_bytes().to_vec())
+                        .map_err(|e| runtime_error(e, RuntimeError::Other))
+                });
+                Ok(ExecSession {
+                    id: exec.id,
+                    output: output.boxed(),
+                    input,
+                })
+            }
+            StartExecResults::Detached => Err(RuntimeError::Other(format!(
+                "exec {} started detached",
+                exec.id
+            ))),
         }
+    }
 
-        return DockerResult {
-            error: None,
-            action: "stop".to_string(),
-            container_id: id.to_string(),
-            result: "success".to_string(),
+    async fn exec_exit_code(&self, exec_id: &str) -> Result<Option<i64>, RuntimeError> {
+        let res = self
+            .client
+            .inspect_exec(exec_id)
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+        if res.running.unwrap_or(false) {
+            return Ok(None);
+        }
+        Ok(res.exit_code)
+    }
+
+    async fn resize_exec(&self, exec_id: &str, rows: u16, cols: u16) -> Result<(), RuntimeError> {
+        let options = ResizeExecOptions {
+            height: rows,
+            width: cols,
         };
+        self.client
+            .resize_exec(exec_id, options)
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))
     }
-}
 
-pub struct DockerResult {
-    pub error: Option<String>,
-    pub action: String,
-    pub container_id: String,
-    pub result: String,
+    async fn list_volumes(&self) -> Result<Vec<VolumeInfo>, RuntimeError> {
+        let res = self
+            .client
+            .list_volumes(None::<ListVolumesOptions<String>>)
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+        let volumes = res.volumes.unwrap_or_default();
+        Ok(volumes.into_iter().map(VolumeInfo::from).collect())
+    }
+
+    async fn create_volume(&self, name: &str) -> Result<VolumeInfo, RuntimeError> {
+        let options = CreateVolumeOptions {
+            name,
+            ..Default::default()
+        };
+        let volume = self
+            .client
+            .create_volume(options)
+            .await
+            .map_err(|e| runtime_error(e, RuntimeError::Other))?;
+        Ok(volume.into())
+    }
+
+    async fn remove_volume(&self, name: &str) -> Result<(), RuntimeError> {
+        let options = RemoveVolumeOptions { force: false };
+        self.client
+            .remove_volume(name, Some(options))
+            .await
+            .map_err(|e| match e {
+                DockerResponseServerError {
+                    status_code: 409,
+                    message,
+                } => RuntimeError::InUse(message),
+                e => runtime_error(e, RuntimeError::Other),
+            })
+    }
 }
 
-impl DockerResult {
-    pub fn error(err: &str) -> Self {
-        DockerResult {
-            error: Some(err.to_string()),
-            action: "".to_string(),
-            container_id: "".to_string(),
-            result: "".to_string(),
-        }
+/// Docker puts the time a line was written in front of it when asked for
+/// `timestamps`, "2025-01-01T12:00:00.123456789Z hello".
+fn split_timestamp(line: &str) -> (Option<DateTime<Utc>>, String) {
+    let parsed = line.split_once(' ').and_then(|(time, message)| {
+        let time = DateTime::parse_from_rfc3339(time).ok()?;
+        Some((time.with_timezone(&Utc), message))
+    });
+    match parsed {
+        Some((time, message)) => (Some(time), message.to_string()),
+        None => (None, line.to_string()),
     }
-    pub fn success(action: &str, container_id: &str, result: &str) -> Self {
-        DockerResult {
-            error: None,
-            action: action.to_string(),
-            container_id: container_id.to_string(),
-            result: result.to_string(),
-        }
+}
+
+/// Sorts a bollard error into a `RuntimeError`. Errors that are not a missing
+/// container or a connection problem are wrapped with `fallback`, which should
+/// say what the runtime was doing when it failed.
+fn runtime_error(e: bollard::errors::Error, fallback: fn(String) -> RuntimeError) -> RuntimeError {
+    use bollard::errors::Error as E;
+    match e {
+        E::DockerResponseServerError {
+            status_code: 404,
+            message,
+        } => RuntimeError::NotFound(message),
+        E::HyperLegacyError { .. }
+        | E::IOError { .. }
+        | E::SocketNotFoundError(_)
+        | E::RequestTimeoutError => RuntimeError::DaemonUnreachable(e.to_string()),
+        e => fallback(e.to_string()),
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::task::ProbeAction;
 
     #[test]
     fn test_serilize() {
@@ -372,7 +1119,10 @@ mod test {
             (State::Pending, "pending"),
             (State::Scheduled, "scheduled"),
             (State::Running, "running"),
+            (State::Stopping, "stopping"),
+            (State::Restarting, "restarting"),
             (State::Completed, "completed"),
+            (State::Cancelled, "cancelled"),
         ];
 
         for (state, expected) in states {
@@ -393,14 +1143,18 @@ mod test {
             disk: 10,
             exposed_ports: HashSet::new(),
             port_bindings: HashMap::new(),
-            restart_policy: "always".to_string(),
+            restart_policy: RestartPolicy::Always,
             start_time: Utc::now(),
             finish_time: None,
+            ..Default::default()
         };
         let serialized = serde_json::to_string(&task).unwrap();
-        println!("Serialized task: {}", serialized);
         let deserialized: Task = serde_json::from_str(&serialized).unwrap();
-        println!("Deserialized task: {:?}", deserialized);
+        assert_eq!(deserialized.id, task.id);
+        assert_eq!(deserialized.name, task.name);
+        assert_eq!(deserialized.state, task.state);
+        assert_eq!(deserialized.memory, task.memory);
+        assert_eq!(deserialized.start_time, task.start_time);
 
         let task_str = r#"{
         "id": "01a0abaa-0219-4847-b0ba-92fdf4570a57",
@@ -413,8 +1167,295 @@ mod test {
         }
     }"#;
         let task: TaskEvent = serde_json::from_str(task_str).unwrap();
-        println!("Deserialized task: {:#?}", task);
+        assert_eq!(task.state, State::Running);
+        assert_eq!(task.task.name, "test-chapter-5");
+        assert_eq!(task.task.image, "strm/helloworld");
+    }
+
+    fn roundtrip(task: &Task) -> (String, Task) {
+        let serialized = serde_json::to_string(task).unwrap();
+        let deserialized = serde_json::from_str(&serialized).unwrap();
+        (serialized, deserialized)
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        let task = Task {
+            exit_code: Some(1),
+            failure_reason: Some(FailureReason::ImagePull("not found".to_string())),
+            attempts: 1,
+            ..Default::default()
+        };
+        let (_, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.exit_code, Some(1));
+        assert_eq!(deserialized.failure_reason, task.failure_reason);
+        assert_eq!(deserialized.attempts, 1);
+
+        let reason = serde_json::to_string(&FailureReason::OomKilled).unwrap();
+        assert_eq!(reason, r#"{"kind":"oom_killed"}"#);
+    }
+
+    #[test]
+    fn test_spec_label() {
+        let mut task = Task {
+            image: "postgres:16".to_string(),
+            env: HashMap::from([("PGPASSWORD".to_string(), "hunter2".to_string())]),
+            ..Default::default()
+        };
+        task.transition(State::Scheduled, "sent to worker w1", Actor::Reconciler)
+            .unwrap();
+        let config = new_config(&task);
+        let label = &config.labels[TASK_SPEC_LABEL];
+        assert!(!label.contains("hunter2"));
+
+        let spec: Task = serde_json::from_str(label).unwrap();
+        assert_eq!(spec.id, task.id);
+        assert_eq!(spec.image, task.image);
+        assert_eq!(spec.state, State::Scheduled);
+        assert!(spec.history.is_empty());
+    }
+
+    #[test]
+    fn test_container_config() {
+        let task = Task {
+            cmd: vec!["-c".to_string(), "echo $GREETING".to_string()],
+            entrypoint: vec!["/bin/sh".to_string()],
+            env: HashMap::from([("GREETING".to_string(), "hello".to_string())]),
+            working_dir: "/tmp".to_string(),
+            ..Default::default()
+        };
+        let (_, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.env, task.env);
+
+        let config = new_config(&task);
+        assert_eq!(config.env, vec!["GREETING=hello".to_string()]);
+        assert_eq!(config.cmd, task.cmd);
+        assert_eq!(config.entrypoint, task.entrypoint);
+        assert_eq!(config.working_dir, "/tmp");
+    }
+
+    #[test]
+    fn test_port_bindings() {
+        let task = Task {
+            port_bindings: HashMap::from([(
+                "80/tcp".to_string(),
+                PortBinding {
+                    host_ip: "127.0.0.1".to_string(),
+                    host_port: 8080,
+                },
+            )]),
+            ..Default::default()
+        };
+        let (_, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.port_bindings, task.port_bindings);
+
+        let port: Port = "53/udp".parse().unwrap();
+        assert_eq!(port.number, 53);
+        assert_eq!(port.protocol, Protocol::Udp);
+        assert!("http".parse::<Port>().is_err());
+    }
+
+    #[test]
+    fn test_volumes() {
+        let task = Task {
+            volumes: vec![VolumeMount {
+                kind: MountKind::Volume,
+                source: "pgdata".to_string(),
+                target: "/var/lib/postgresql/data".to_string(),
+                read_only: false,
+            }],
+            ..Default::default()
+        };
+        let (_, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.volumes, task.volumes);
+    }
+
+    #[test]
+    fn test_probes() {
+        let task = Task {
+            liveness: Some(Probe {
+                action: ProbeAction::Http {
+                    port: Port {
+                        number: 80,
+                        protocol: Protocol::Tcp,
+                    },
+                    path: "/health".to_string(),
+                },
+                interval_secs: 5,
+                timeout_secs: 1,
+                failure_threshold: 3,
+            }),
+            ..Default::default()
+        };
+        let (_, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.liveness, task.liveness);
+
+        let task_str = r#"{
+            "readiness": {"type": "tcp", "port": {"number": 80, "protocol": "Tcp"}}
+        }"#;
+        let task: Task = serde_json::from_str(task_str).unwrap();
+        let readiness = task.readiness.unwrap();
+        assert_eq!(readiness.interval_secs, 10);
+        assert_eq!(readiness.failure_threshold, 3);
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let task = Task {
+            restart_policy: RestartPolicy::OnFailure { max_retries: 3 },
+            restart_count: 2,
+            ..Default::default()
+        };
+        let (_, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.restart_policy, task.restart_policy);
+        assert_eq!(deserialized.restart_count, 2);
+        let policy = serde_json::to_string(&task.restart_policy).unwrap();
+        assert_eq!(policy, r#"{"on-failure":{"max_retries":3}}"#);
+
+        let task: Task = serde_json::from_str(r#"{"restart_policy": "unless-stopped"}"#).unwrap();
+        assert_eq!(task.restart_policy, RestartPolicy::UnlessStopped);
+    }
+
+    #[test]
+    fn test_stop_outcome() {
+        let task = Task {
+            stop_signal: "SIGINT".to_string(),
+            stop_grace_secs: 60,
+            stop_outcome: Some(StopOutcome::Killed),
+            ..Default::default()
+        };
+        let (serialized, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.stop_outcome, task.stop_outcome);
+        assert!(serialized.contains(r#""stop_outcome":"killed""#));
+        let options = deserialized.stop_options();
+        assert_eq!(options.signal, "SIGINT");
+        assert_eq!(options.grace, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_driver() {
+        let task = Task {
+            driver: Driver::Process,
+            cpu_time_secs: Some(60),
+            ..Default::default()
+        };
+        let (serialized, deserialized) = roundtrip(&task);
+        assert_eq!(deserialized.driver, Driver::Process);
+        assert_eq!(deserialized.cpu_time_secs, Some(60));
+        assert!(serialized.contains(r#""driver":"process""#));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut task = Task::default();
+        task.transition(State::Scheduled, "sent to worker w1", Actor::Reconciler)
+            .unwrap();
+        let mut on_worker = task.clone();
+        on_worker
+            .transition(State::Running, "container started", Actor::Worker)
+            .unwrap();
+        task.transition(State::Stopping, "stop requested", Actor::Api)
+            .unwrap();
+        assert!(task.transition(State::Pending, "nope", Actor::Api).is_err());
+
+        task.merge_history(&on_worker.history);
+        let steps: Vec<_> = task
+            .history
+            .iter()
+            .map(|t| (t.from, t.to, t.actor))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (State::Pending, State::Scheduled, Actor::Reconciler),
+                (State::Scheduled, State::Running, Actor::Worker),
+                (State::Scheduled, State::Stopping, Actor::Api),
+            ]
+        );
+
+        for _ in 0..MAX_HISTORY {
+            task.transition(State::Stopping, "stop requested", Actor::Api)
+                .unwrap();
+        }
+        assert_eq!(task.history.len(), MAX_HISTORY);
+        assert_eq!(task.history[0].to, State::Stopping);
+    }
+
+    #[test]
+    fn test_image_reference() {
+        assert_eq!(split_image("postgres:latest"), ("postgres", "latest"));
+        assert_eq!(split_image("postgres"), ("postgres", ""));
+        assert_eq!(
+            split_image("localhost:5000/team/app:1.0"),
+            ("localhost:5000/team/app", "1.0")
+        );
+        assert_eq!(
+            split_image("localhost:5000/app"),
+            ("localhost:5000/app", "")
+        );
+
+        assert_eq!(registry_host("postgres:latest"), "docker.io");
+        assert_eq!(registry_host("strm/helloworld-http"), "docker.io");
+        assert_eq!(registry_host("ghcr.io/org/app:1.0"), "ghcr.io");
+        assert_eq!(registry_host("localhost:5000/app"), "localhost:5000");
+
+        let auth = RegistryAuth {
+            name: "ci".to_string(),
+            username: "bot".to_string(),
+            password: "hunter2".to_string(),
+            identity_token: None,
+        };
+        assert!(!format!("{:?}", auth).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_pull_progress() {
+        let layer = |id: &str, status: &str, current: i64, total: i64| CreateImageInfo {
+            id: Some(id.to_string()),
+            status: Some(status.to_string()),
+            progress_detail: Some(bollard::models::ProgressDetail {
+                current: Some(current),
+                total: Some(total),
+            }),
+            ..Default::default()
+        };
+
+        let mut pull = PullProgress::default();
+        assert!(!pull.update(&CreateImageInfo {
+            id: Some("16".to_string()),
+            status: Some("Pulling from library/postgres".to_string()),
+            ..Default::default()
+        }));
+        assert!(pull.update(&layer("a", "Downloading", 50, 100)));
+        assert!(pull.update(&layer("b", "Pull complete", 0, 0)));
+        assert!(pull.update(&layer("c", "Pulling fs layer", 0, 0)));
+        assert_eq!(pull.percent(), 50.0);
+
+        assert!(pull.update(&layer("a", "Pull complete", 0, 0)));
+        assert!(pull.update(&layer("c", "Already exists", 0, 0)));
+        assert_eq!(pull.percent(), 100.0);
+    }
+
+    #[test]
+    fn test_pull_progress_extracting() {
+        let layer = |status: &str, current: i64, total: i64| CreateImageInfo {
+            id: Some("a".to_string()),
+            status: Some(status.to_string()),
+            progress_detail: Some(bollard::models::ProgressDetail {
+                current: Some(current),
+                total: Some(total),
+            }),
+            ..Default::default()
+        };
 
-        assert_eq!(1, 0);
+        let mut pull = PullProgress::default();
+        pull.update(&layer("Downloading", 80, 100));
+        assert_eq!(pull.percent(), 80.0);
+        pull.update(&layer("Download complete", 0, 0));
+        assert_eq!(pull.percent(), 100.0);
+        // extracting starts over at 0 bytes, the pull doesn't go backwards
+        pull.update(&layer("Extracting", 1, 300));
+        assert_eq!(pull.percent(), 100.0);
+        assert_eq!(pull.layers["a"].current, 80);
     }
 }
@@ -0,0 +1,586 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::task::JoinHandle;
+use tracing::info;
+use uuid::Uuid;
+use wasmtime::{Engine, Linker, Module, ResourceLimiter, Store, UpdateDeadline};
+use wasmtime_wasi::pipe::AsyncWriteStream;
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{AsyncStdoutStream, I32Exit, WasiCtxBuilder};
+
+use super::output::{self, Output};
+use super::runtime::{
+    cpu_percent, ContainerHandle, ContainerInfo, ContainerRuntime, ContainerStats,
+    ContainerSummary, ExecOptions, ExecSession, LogOptions, LogSource, LogStream, PullProgressFn,
+    RuntimeError, StopOptions, StopOutcome, VolumeInfo,
+};
+use super::task::Config;
+
+// how often running modules are interrupted, to yield to other tasks and to
+// count their cpu time
+const TICK: Duration = Duration::from_millis(10);
+// how much output a module can write before it waits for it to be collected
+const OUTPUT_BUFFER: usize = 64 * 1024;
+
+/// Runs WASI (preview 1) modules inside the worker, for small sandboxed jobs
+/// that would spend most of their time waiting for a container to start. A
+/// task's `image` is the path of its module (`.wasm`, or `.wat` text) on the
+/// worker, `cmd` its arguments and `env` its environment. Modules get no
+/// filesystem or network access.
+///
+/// `memory` caps the module's linear memory. `cpu_time` is counted in ticks of
+/// the engine's epoch, a module that is still computing once it is used up
+/// is ended with exit code 1.
+#[derive(Debug)]
+pub struct WasmRuntime {
+    engine: Engine,
+    // compiled modules, by image, with when the file was last modified
+    modules: Mutex<HashMap<String, (SystemTime, Module)>>,
+    instances: Arc<Mutex<HashMap<String, Instance>>>,
+}
+
+#[derive(Debug)]
+struct Instance {
+    name: String,
+    labels: HashMap<String, String>,
+    running: bool,
+    exit_code: Option<i64>,
+    finished_at: Option<DateTime<Utc>>,
+    oom_killed: bool,
+    output: Arc<Mutex<Output>>,
+    // taken by `stop`
+    task: Option<JoinHandle<()>>,
+    usage: Arc<Usage>,
+    memory_limit: u64,
+    // cpu time at the previous `stats`
+    last_cpu: Option<(Duration, DateTime<Utc>)>,
+}
+
+/// What a module uses, kept up to date by its store for `stats`.
+#[derive(Debug, Default)]
+struct Usage {
+    memory: AtomicU64,
+    ticks: AtomicU64,
+}
+
+/// What a module's store holds.
+struct WasmState {
+    wasi: WasiP1Ctx,
+    memory: MemoryLimit,
+    ticks: u64,
+    max_ticks: Option<u64>,
+    usage: Arc<Usage>,
+}
+
+struct MemoryLimit {
+    max_bytes: Option<usize>,
+    // a growth was refused, the module most likely fails because of it
+    exceeded: bool,
+    usage: Arc<Usage>,
+}
+
+impl ResourceLimiter for MemoryLimit {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        if self.max_bytes.is_some_and(|max| desired > max) {
+            self.exceeded = true;
+            return Ok(false);
+        }
+        self.usage.memory.store(desired as u64, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl WasmRuntime {
+    pub fn new() -> Result<Self, RuntimeError> {
+        let mut config = wasmtime::Config::new();
+        config.async_support(true).epoch_interruption(true);
+        let engine = Engine::new(&config).map_err(|e| RuntimeError::Other(e.to_string()))?;
+
+        // stops ticking once the runtime is dropped
+        let weak = engine.weak();
+        std::thread::spawn(move || {
+            while let Some(engine) = weak.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                std::thread::sleep(TICK);
+            }
+        });
+
+        Ok(WasmRuntime {
+            engine,
+            modules: Mutex::new(HashMap::new()),
+            instances: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    fn store(
+        &self,
+        config: &Config,
+        stdout: AsyncWriteStream,
+        stderr: AsyncWriteStream,
+        usage: Arc<Usage>,
+    ) -> Store<WasmState> {
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.arg(&config.image)
+            .args(&config.cmd)
+            .stdout(AsyncStdoutStream::new(stdout))
+            .stderr(AsyncStdoutStream::new(stderr));
+        for (k, v) in config.env.iter().filter_map(|e| e.split_once('=')) {
+            wasi.env(k, v);
+        }
+
+        let state = WasmState {
+            wasi: wasi.build_p1(),
+            memory: MemoryLimit {
+                max_bytes: (config.memory > 0).then_some(config.memory as usize),
+                exceeded: false,
+                usage: usage.clone(),
+            },
+            ticks: 0,
+            max_ticks: config
+                .cpu_time
+                .map(|t| (t.as_millis() / TICK.as_millis()) as u64),
+            usage,
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.memory);
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(|mut store| {
+            let state = store.data_mut();
+            state.ticks += 1;
+            state.usage.ticks.store(state.ticks, Ordering::Relaxed);
+            if state.max_ticks.is_some_and(|max| state.ticks > max) {
+                return Err(wasmtime::Error::msg("cpu time limit exceeded"));
+            }
+            Ok(UpdateDeadline::Yield(1))
+        });
+        store
+    }
+}
+
+/// Runs the module's `_start` and returns its exit code.
+async fn start(store: &mut Store<WasmState>, module: &Module) -> wasmtime::Result<i64> {
+    let mut linker = Linker::new(store.engine());
+    preview1::add_to_linker_async(&mut linker, |state: &mut WasmState| &mut state.wasi)?;
+    let instance = linker.instantiate_async(&mut *store, module).await?;
+    let start = instance.get_typed_func::<(), ()>(&mut *store, "_start")?;
+    match start.call_async(&mut *store, ()).await {
+        Ok(()) => Ok(0),
+        Err(e) => match e.downcast_ref::<I32Exit>() {
+            Some(exit) => Ok(exit.0.into()),
+            None => Err(e),
+        },
+    }
+}
+
+fn not_found(id: &str) -> RuntimeError {
+    RuntimeError::NotFound(format!("no such wasm instance: {}", id))
+}
+
+fn unsupported(what: &str) -> RuntimeError {
+    RuntimeError::Other(format!("{} is not supported by the wasm driver", what))
+}
+
+#[async_trait]
+impl ContainerRuntime for WasmRuntime {
+    /// Compiles the module, again only once its file was modified.
+    async fn pull(&self, config: &Config, _progress: &PullProgressFn) -> Result<(), RuntimeError> {
+        let modified = tokio::fs::metadata(&config.image)
+            .await
+            .and_then(|m| m.modified())
+            .map_err(|e| RuntimeError::ImagePull(format!("{}: {}", config.image, e)))?;
+        let cached = self.modules.lock().unwrap().get(&config.image).map(|m| m.0);
+        if cached == Some(modified) {
+            return Ok(());
+        }
+        let engine = self.engine.clone();
+        let image = config.image.clone();
+        let module = tokio::task::spawn_blocking(move || Module::from_file(&engine, &image)).await;
+        let module = match module {
+            Ok(Ok(module)) => module,
+            Ok(Err(e)) => return Err(RuntimeError::ImagePull(format!("{:#}", e))),
+            Err(e) => return Err(RuntimeError::ImagePull(e.to_string())),
+        };
+        let mut modules = self.modules.lock().unwrap();
+        modules.insert(config.image.clone(), (modified, module));
+        Ok(())
+    }
+
+    async fn run(&self, config: &Config) -> Result<ContainerHandle, RuntimeError> {
+        if !config.volumes.is_empty() {
+            return Err(RuntimeError::Create(unsupported("volumes").to_string()));
+        }
+        let module = self
+            .modules
+            .lock()
+            .unwrap()
+            .get(&config.image)
+            .map(|m| m.1.clone());
+        let module = module.ok_or_else(|| {
+            RuntimeError::Create(format!("module {} was not pulled", config.image))
+        })?;
+
+        let id = Uuid::new_v4().simple().to_string();
+        let output = Arc::new(Mutex::new(Output::new()));
+        let usage = Arc::new(Usage::default());
+        {
+            let mut instances = self.instances.lock().unwrap();
+            if instances.values().any(|i| i.name == config.name) {
+                let e = format!("instance name {:?} is already in use", config.name);
+                return Err(RuntimeError::CreateConflict(e));
+            }
+            let instance = Instance {
+                name: config.name.clone(),
+                labels: config.labels.clone(),
+                running: true,
+                exit_code: None,
+                finished_at: None,
+                oom_killed: false,
+                output: output.clone(),
+                task: None,
+                usage: usage.clone(),
+                memory_limit: config.memory.max(0) as u64,
+                last_cpu: None,
+            };
+            instances.insert(id.clone(), instance);
+        }
+
+        let (stdout, stdout_reader) = tokio::io::duplex(OUTPUT_BUFFER);
+        let (stderr, stderr_reader) = tokio::io::duplex(OUTPUT_BUFFER);
+        let stdout = AsyncWriteStream::new(OUTPUT_BUFFER, stdout);
+        let stderr = AsyncWriteStream::new(OUTPUT_BUFFER, stderr);
+        let mut store = self.store(config, stdout, stderr, usage);
+        let stdout = output::collect(output.clone(), LogSource::Stdout, stdout_reader);
+        let stderr = output::collect(output.clone(), LogSource::Stderr, stderr_reader);
+        let collectors = futures::future::join(tokio::spawn(stdout), tokio::spawn(stderr));
+
+        let instances = self.instances.clone();
+        let instance_id = id.clone();
+        let task = tokio::spawn(async move {
+            let result = start(&mut store, &module).await;
+            let oom_killed = store.data().memory.exceeded;
+            // closes the module's end of stdout and stderr
+            drop(store);
+            let _ = collectors.await;
+
+            let mut output = output.lock().unwrap();
+            let exit_code = match result {
+                Ok(code) => code,
+                Err(e) => {
+                    output.push(LogSource::Stderr, format!("{:#}\n", e).as_bytes());
+                    1
+                }
+            };
+            output.close();
+            if let Some(i) = instances.lock().unwrap().get_mut(&instance_id) {
+                i.running = false;
+                i.exit_code = Some(exit_code);
+                i.finished_at = Some(Utc::now());
+                i.oom_killed = oom_killed && exit_code != 0;
+            }
+        });
+        if let Some(i) = self.instances.lock().unwrap().get_mut(&id) {
+            i.task = Some(task);
+        }
+
+        info!("[WORKER] Wasm module {} started as {}", config.image, id);
+        Ok(ContainerHandle { id })
+    }
+
+    /// Modules can't handle signals, one that is still running is ended right
+    /// away, whatever the grace period.
+    async fn stop(&self, id: &str, _options: &StopOptions) -> Result<StopOutcome, RuntimeError> {
+        let task = {
+            let mut instances = self.instances.lock().unwrap();
+            let i = instances.get_mut(id).ok_or_else(|| not_found(id))?;
+            match i.task.take() {
+                Some(task) if i.running => task,
+                _ => return Ok(StopOutcome::Exited),
+            }
+        };
+
+        task.abort();
+        // it may have finished on its own in the meantime
+        if task.await.is_ok() {
+            return Ok(StopOutcome::Exited);
+        }
+        let mut instances = self.instances.lock().unwrap();
+        if let Some(i) = instances.get_mut(id) {
+            i.running = false;
+            i.finished_at = Some(Utc::now());
+            i.output.lock().unwrap().close();
+        }
+        Ok(StopOutcome::Killed)
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), RuntimeError> {
+        let mut instances = self.instances.lock().unwrap();
+        let i = instances.get(id).ok_or_else(|| not_found(id))?;
+        if i.running {
+            return Err(RuntimeError::InUse(format!("instance {} is running", id)));
+        }
+        instances.remove(id);
+        Ok(())
+    }
+
+    async fn inspect(&self, id: &str) -> Result<ContainerInfo, RuntimeError> {
+        let instances = self.instances.lock().unwrap();
+        let i = instances.get(id).ok_or_else(|| not_found(id))?;
+        Ok(ContainerInfo {
+            id: id.to_string(),
+            running: i.running,
+            exit_code: i.exit_code,
+            oom_killed: i.oom_killed,
+            finished_at: i.finished_at,
+            ports: HashMap::new(),
+        })
+    }
+
+    /// Only knows what this runtime started, modules aren't found again after
+    /// the worker restarts.
+    async fn list(&self, label: &str) -> Result<Vec<ContainerSummary>, RuntimeError> {
+        let instances = self.instances.lock().unwrap();
+        let found = instances
+            .iter()
+            .filter(|(_, p)| p.labels.contains_key(label))
+            .map(|(id, p)| ContainerSummary {
+                id: id.clone(),
+                name: p.name.clone(),
+                running: p.running,
+                labels: p.labels.clone(),
+            });
+        Ok(found.collect())
+    }
+
+    /// Cpu time is counted in epoch ticks, while the module runs code. Modules
+    /// have no network or files.
+    async fn stats(&self, id: &str) -> Result<ContainerStats, RuntimeError> {
+        let mut instances = self.instances.lock().unwrap();
+        let i = instances.get_mut(id).ok_or_else(|| not_found(id))?;
+        let cpu_time = TICK * i.usage.ticks.load(Ordering::Relaxed) as u32;
+        let stats = ContainerStats {
+            cpu_percent: cpu_percent(i.last_cpu, cpu_time),
+            memory_bytes: i.usage.memory.load(Ordering::Relaxed),
+            memory_limit_bytes: i.memory_limit,
+            sampled_at: Utc::now(),
+            ..Default::default()
+        };
+        i.last_cpu = Some((cpu_time, stats.sampled_at));
+        Ok(stats)
+    }
+
+    async fn logs(&self, id: &str, options: &LogOptions) -> Result<LogStream, RuntimeError> {
+        let instances = self.instances.lock().unwrap();
+        let i = instances.get(id).ok_or_else(|| not_found(id))?;
+        let logs = i.output.lock().unwrap().stream(options);
+        Ok(logs)
+    }
+
+    async fn exec(&self, _id: &str, _options: &ExecOptions) -> Result<ExecSession, RuntimeError> {
+        Err(unsupported("exec"))
+    }
+
+    async fn exec_exit_code(&self, _exec_id: &str) -> Result<Option<i64>, RuntimeError> {
+        Err(unsupported("exec"))
+    }
+
+    async fn resize_exec(
+        &self,
+        _exec_id: &str,
+        _rows: u16,
+        _cols: u16,
+    ) -> Result<(), RuntimeError> {
+        Err(unsupported("exec"))
+    }
+
+    async fn list_volumes(&self) -> Result<Vec<VolumeInfo>, RuntimeError> {
+        Ok(vec![])
+    }
+
+    async fn create_volume(&self, _name: &str) -> Result<VolumeInfo, RuntimeError> {
+        Err(unsupported("volumes"))
+    }
+
+    async fn remove_volume(&self, _name: &str) -> Result<(), RuntimeError> {
+        Err(unsupported("volumes"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt;
+
+    const GREETER: &str = r#"(module
+      (import "wasi_snapshot_preview1" "fd_write"
+        (func $fd_write (param i32 i32 i32 i32) (result i32)))
+      (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 16) "hello\n")
+      (data (i32.const 32) "oops\n")
+      (func (export "_start")
+        (i32.store (i32.const 0) (i32.const 16))
+        (i32.store (i32.const 4) (i32.const 6))
+        (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+        (i32.store (i32.const 0) (i32.const 32))
+        (i32.store (i32.const 4) (i32.const 5))
+        (drop (call $fd_write (i32.const 2) (i32.const 0) (i32.const 1) (i32.const 8)))
+        (call $proc_exit (i32.const 3))))"#;
+
+    const GROWER: &str = r#"(module
+      (memory 1)
+      (func (export "_start")
+        (loop $grow
+          (br_if $grow (i32.ne (memory.grow (i32.const 1)) (i32.const -1))))
+        unreachable))"#;
+
+    const SPINNER: &str = r#"(module
+      (func (export "_start") (loop $spin (br $spin))))"#;
+
+    async fn pulled(runtime: &WasmRuntime, name: &str, wat: &str) -> Config {
+        let dir = std::env::temp_dir().join(format!("cube-wasm-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join(format!("{}.wat", name));
+        std::fs::write(&image, wat).unwrap();
+        let config = Config {
+            name: name.to_string(),
+            image: image.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        runtime.pull(&config, &|_| {}).await.unwrap();
+        config
+    }
+
+    async fn wait_exit(runtime: &WasmRuntime, id: &str) -> ContainerInfo {
+        loop {
+            let info = runtime.inspect(id).await.unwrap();
+            if !info.running {
+                return info;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn logs(runtime: &WasmRuntime, id: &str) -> Vec<(LogSource, String)> {
+        let logs = runtime.logs(id, &LogOptions::default()).await;
+        let logs: Vec<_> = logs.unwrap().try_collect().await.unwrap();
+        logs.into_iter().map(|c| (c.source, c.message)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_run_module() {
+        let runtime = WasmRuntime::new().unwrap();
+        let config = pulled(&runtime, "greeter", GREETER).await;
+        let handle = runtime.run(&config).await.unwrap();
+        let err = runtime.run(&config).await.unwrap_err();
+        assert!(matches!(err, RuntimeError::CreateConflict(_)));
+
+        let info = wait_exit(&runtime, &handle.id).await;
+        assert_eq!(info.exit_code, Some(3));
+        assert!(!info.oom_killed);
+        // its one page of memory
+        let stats = runtime.stats(&handle.id).await.unwrap();
+        assert_eq!(stats.memory_bytes, 64 * 1024);
+        let mut lines = logs(&runtime, &handle.id).await;
+        lines.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            lines,
+            [
+                (LogSource::Stdout, "hello\n".to_string()),
+                (LogSource::Stderr, "oops\n".to_string())
+            ]
+        );
+        runtime.remove(&handle.id).await.unwrap();
+
+        let config = Config {
+            image: "/nonexistent/module.wasm".to_string(),
+            ..config
+        };
+        let err = runtime.pull(&config, &|_| {}).await.unwrap_err();
+        assert!(matches!(err, RuntimeError::ImagePull(_)));
+    }
+
+    #[tokio::test]
+    async fn test_module_replaced() {
+        let runtime = WasmRuntime::new().unwrap();
+        let config = pulled(&runtime, "greeter", GREETER).await;
+        let handle = runtime.run(&config).await.unwrap();
+        assert_eq!(wait_exit(&runtime, &handle.id).await.exit_code, Some(3));
+        runtime.remove(&handle.id).await.unwrap();
+
+        // a new version of the module, in place of the old one
+        let exits = r#"(module
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (memory (export "memory") 1)
+          (func (export "_start") (call $proc_exit (i32.const 7))))"#;
+        std::fs::write(&config.image, exits).unwrap();
+        let file = std::fs::File::options().write(true).open(&config.image);
+        let later = SystemTime::now() + Duration::from_secs(1);
+        file.unwrap().set_modified(later).unwrap();
+        runtime.pull(&config, &|_| {}).await.unwrap();
+        let handle = runtime.run(&config).await.unwrap();
+        assert_eq!(wait_exit(&runtime, &handle.id).await.exit_code, Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_module_limits() {
+        let runtime = WasmRuntime::new().unwrap();
+        let config = Config {
+            memory: 1024 * 1024,
+            ..pulled(&runtime, "grower", GROWER).await
+        };
+        let handle = runtime.run(&config).await.unwrap();
+        let info = wait_exit(&runtime, &handle.id).await;
+        assert_eq!(info.exit_code, Some(1));
+        assert!(info.oom_killed);
+
+        let config = Config {
+            cpu_time: Some(Duration::from_millis(200)),
+            ..pulled(&runtime, "spinner", SPINNER).await
+        };
+        let handle = runtime.run(&config).await.unwrap();
+        let info = wait_exit(&runtime, &handle.id).await;
+        assert_eq!(info.exit_code, Some(1));
+        assert!(!info.oom_killed);
+        let lines = logs(&runtime, &handle.id).await;
+        assert!(lines
+            .iter()
+            .any(|(source, message)| *source == LogSource::Stderr
+                && message.contains("cpu time limit exceeded")));
+    }
+
+    #[tokio::test]
+    async fn test_stop_module() {
+        let runtime = WasmRuntime::new().unwrap();
+        let config = pulled(&runtime, "spinner", SPINNER).await;
+        let handle = runtime.run(&config).await.unwrap();
+        let err = runtime.remove(&handle.id).await.unwrap_err();
+        assert!(matches!(err, RuntimeError::InUse(_)));
+
+        let outcome = runtime.stop(&handle.id, &StopOptions::default()).await;
+        assert_eq!(outcome.unwrap(), StopOutcome::Killed);
+        assert!(!runtime.inspect(&handle.id).await.unwrap().running);
+        let outcome = runtime.stop(&handle.id, &StopOptions::default()).await;
+        assert_eq!(outcome.unwrap(), StopOutcome::Exited);
+        runtime.remove(&handle.id).await.unwrap();
+    }
+}
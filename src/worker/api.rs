@@ -1,18 +1,66 @@
 use std::sync::Arc;
 
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::Response;
 use axum::routing::{delete, get, post};
 use axum::{Json, Router};
-use tracing::info;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, info};
 use uuid::Uuid;
 
+use super::capacity::Resources;
+use super::ports::{PortAllocation, PortError};
 use super::stats::Stats;
 use super::worker::{self, Worker};
-use crate::task::{self, Task, TaskEvent};
+use crate::task::{
+    self, ContainerRuntime, ContainerStats, ExecOptions, ExecSession, LogOptions, PullProgress,
+    RuntimeError, Task, TaskEvent, Transition, VolumeInfo,
+};
 
 type AppState = State<Arc<Worker>>;
 
+/// Body of every error response. `reason` is meant for programs, `message`
+/// for humans.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiError {
+    pub reason: String,
+    pub message: String,
+}
+
+type ApiResult<T> = Result<T, (StatusCode, Json<ApiError>)>;
+
+fn api_error(e: worker::Error) -> (StatusCode, Json<ApiError>) {
+    let (status, reason) = match &e {
+        worker::Error::TaskNotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+        worker::Error::Ports(PortError::Conflict { .. }) => (StatusCode::CONFLICT, "port_conflict"),
+        worker::Error::Ports(PortError::Exhausted) => (StatusCode::CONFLICT, "ports_exhausted"),
+        worker::Error::Ports(PortError::InvalidPort(_)) => {
+            (StatusCode::BAD_REQUEST, "invalid_port")
+        }
+        worker::Error::InvalidTransition(..) => (StatusCode::CONFLICT, "invalid_transition"),
+        worker::Error::DriverUnavailable(_) => (StatusCode::BAD_REQUEST, "driver_unavailable"),
+        worker::Error::Capacity(e) => (StatusCode::CONFLICT, e.reason()),
+        worker::Error::NotRunning(_) => (StatusCode::CONFLICT, "not_running"),
+        worker::Error::UnsupportedProbe(_) => (StatusCode::BAD_REQUEST, "unsupported_probe"),
+        worker::Error::Runtime(RuntimeError::NotFound(_)) => (StatusCode::NOT_FOUND, "not_found"),
+        worker::Error::Runtime(RuntimeError::InUse(_)) => (StatusCode::CONFLICT, "in_use"),
+        worker::Error::Runtime(RuntimeError::DaemonUnreachable(_)) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "runtime_unreachable")
+        }
+        worker::Error::Runtime(_) => (StatusCode::INTERNAL_SERVER_ERROR, "runtime_error"),
+    };
+    let body = ApiError {
+        reason: reason.to_string(),
+        message: e.to_string(),
+    };
+    (status, Json(body))
+}
+
 pub struct Api {
     address: String,
     port: u16,
@@ -23,13 +71,29 @@ impl Api {
     pub async fn start(self) {
         let socket = format!("{}:{}", self.address, self.port);
         let listener = tokio::net::TcpListener::bind(socket).await.unwrap();
+        self.serve(listener).await;
+    }
+
+    /// Serves the api on a listener that is already bound.
+    pub async fn serve(self, listener: tokio::net::TcpListener) {
         axum::serve(listener, self.router).await.unwrap();
     }
 }
 
 pub async fn start_api(api: Api, worker: Arc<Worker>) {
+    // tasks and containers from before a restart, before anything else
+    // touches `db`
+    match worker.load_tasks() {
+        Ok(count) => info!("[WORKER] Loaded {} tasks from the store", count),
+        Err(e) => error!("[WORKER] Error loading tasks from the store: {}", e),
+    }
+    worker.adopt_containers().await;
     tokio::spawn(worker::collect_stats(worker.clone()));
     tokio::spawn(worker::run_tasks_loop(worker.clone()));
+    tokio::spawn(worker::inspect_tasks_loop(worker.clone()));
+    tokio::spawn(worker::prune_logs_loop(worker.clone()));
+    tokio::spawn(worker::health_checks_loop(worker.clone()));
+    tokio::spawn(worker::sample_usage_loop(worker.clone()));
 
     api.start().await;
 }
@@ -38,8 +102,18 @@ pub fn setup(address: &str, port: u16, worker: Arc<Worker>) -> Api {
     let router = Router::new()
         .route("/tasks", post(start_task))
         .route("/tasks", get(get_task))
+        .route("/tasks/{task_id}", get(get_task_by_id))
         .route("/tasks/{task_id}", delete(stop_task))
+        .route("/tasks/{task_id}/history", get(get_task_history))
+        .route("/tasks/{task_id}/stats", get(get_task_stats))
+        .route("/tasks/{task_id}/logs", get(get_task_logs))
+        // websocket handshakes are always a GET
+        .route("/tasks/{task_id}/exec", get(exec_task))
         .route("/stats", get(get_stats))
+        .route("/ports", get(get_ports))
+        .route("/volumes", get(list_volumes))
+        .route("/volumes", post(create_volume))
+        .route("/volumes/{name}", delete(delete_volume))
         .with_state(worker);
     Api {
         address: address.to_string(),
@@ -49,45 +123,455 @@ pub fn setup(address: &str, port: u16, worker: Arc<Worker>) -> Api {
 }
 
 // TODO have a default 400 response for all routes
-async fn start_task(State(w): AppState, Json(te): Json<TaskEvent>) -> (StatusCode, Json<Task>) {
-    w.add_task(te.task.clone());
-    info!("[WORKER] Added task {:?}", te.task.id);
-    (StatusCode::CREATED, Json(te.task))
+async fn start_task(
+    State(w): AppState,
+    Json(te): Json<TaskEvent>,
+) -> ApiResult<(StatusCode, Json<Task>)> {
+    let task = w.submit_task(te.task).map_err(api_error)?;
+    info!("[WORKER] Added task {:?}", task.id);
+    Ok((StatusCode::CREATED, Json(task)))
 }
 
-async fn get_task(State(w): AppState) -> Json<Vec<Task>> {
+async fn get_task(State(w): AppState) -> Json<Vec<TaskView>> {
     let tasks = {
         let db = w.db.lock().expect("Failed to lock worker db");
         db.values().cloned().collect::<Vec<Task>>()
     };
     info!("[WORKER] Getting tasks {:?}", tasks);
-    Json(tasks.clone())
+    Json(tasks.into_iter().map(|t| task_view(&w, t)).collect())
+}
+
+/// A task plus what the worker knows about it that is not part of the task
+/// itself.
+#[derive(Debug, Serialize)]
+struct TaskView {
+    #[serde(flatten)]
+    task: Task,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pull: Option<PullProgress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<UsageSummary>,
+}
+
+/// The gist of a task's latest usage sample, the whole of it is on
+/// `/tasks/{id}/stats`.
+#[derive(Debug, Serialize)]
+struct UsageSummary {
+    cpu_percent: f64,
+    memory_bytes: u64,
+}
+
+fn task_view(w: &Worker, task: Task) -> TaskView {
+    let pull = w
+        .pulls
+        .lock()
+        .expect("Failed to lock worker pulls")
+        .get(&task.id)
+        .cloned();
+    let progress = pull.as_ref().map(|p| format!("pulling {}%", p.percent()));
+    let usage = w.usage.lock().expect("Failed to lock worker usage");
+    let usage = usage.get(&task.id).map(|s| UsageSummary {
+        cpu_percent: s.cpu_percent,
+        memory_bytes: s.memory_bytes,
+    });
+    TaskView {
+        task,
+        progress,
+        pull,
+        usage,
+    }
+}
+
+async fn get_task_by_id(
+    State(w): AppState,
+    Path(task_id): Path<Uuid>,
+) -> ApiResult<Json<TaskView>> {
+    let task = {
+        let db = w.db.lock().expect("Failed to lock worker db");
+        db.get(&task_id).cloned()
+    };
+    let task = task.ok_or_else(|| task_not_found(task_id))?;
+    Ok(Json(task_view(&w, task)))
 }
 
-async fn stop_task(
+/// What the task's container uses, as of the latest sample.
+async fn get_task_stats(
     State(w): AppState,
-    Path(task_id): Path<String>,
-) -> Result<StatusCode, StatusCode> {
-    let task_id = Uuid::parse_str(&task_id).map_err(|_| StatusCode::BAD_REQUEST)?;
-    let mut task_to_stop = {
+    Path(task_id): Path<Uuid>,
+) -> ApiResult<Json<ContainerStats>> {
+    let stats = w.task_stats(task_id).await.map_err(api_error)?;
+    Ok(Json(stats))
+}
+
+/// The state changes of a task, oldest first.
+async fn get_task_history(
+    State(w): AppState,
+    Path(task_id): Path<Uuid>,
+) -> ApiResult<Json<Vec<Transition>>> {
+    let db = w.db.lock().expect("Failed to lock worker db");
+    let task = db.get(&task_id).ok_or_else(|| task_not_found(task_id))?;
+    Ok(Json(task.history.clone()))
+}
+
+fn task_not_found(task_id: Uuid) -> (StatusCode, Json<ApiError>) {
+    let body = ApiError {
+        reason: "not_found".to_string(),
+        message: format!("task {} not found", task_id),
+    };
+    (StatusCode::NOT_FOUND, Json(body))
+}
+
+/// Streams the output of the task's container as plain text, chunk by chunk,
+/// for as long as the runtime keeps sending it (see `LogOptions::follow`).
+/// Tasks that are done are served from the worker's log store instead, their
+/// container is gone.
+async fn get_task_logs(
+    State(w): AppState,
+    Path(task_id): Path<Uuid>,
+    Query(options): Query<LogOptions>,
+) -> ApiResult<([(header::HeaderName, &'static str); 1], Body)> {
+    let task = {
         let db = w.db.lock().expect("Failed to lock worker db");
-        db.get(&task_id).cloned().ok_or(StatusCode::NOT_FOUND)?
+        db.get(&task_id).cloned()
     };
+    let task = task.ok_or_else(|| task_not_found(task_id))?;
+    let content_type = [(header::CONTENT_TYPE, "text/plain; charset=utf-8")];
 
-    task_to_stop.state = task::State::Completed;
+    // restarting tasks are between containers until the backoff is over
+    let stored = matches!(
+        task.state,
+        task::State::Completed | task::State::Failed | task::State::Restarting
+    );
+    if stored {
+        let logs = w.logs.read(task_id, &options).map_err(|e| {
+            let body = ApiError {
+                reason: "log_store_error".to_string(),
+                message: e.to_string(),
+            };
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(body))
+        })?;
+        let logs = logs.ok_or_else(|| {
+            let body = ApiError {
+                reason: "not_found".to_string(),
+                message: format!("no logs kept for task {}", task_id),
+            };
+            (StatusCode::NOT_FOUND, Json(body))
+        })?;
+        let body: String = logs.into_iter().map(|c| c.message).collect();
+        return Ok((content_type, Body::from(body)));
+    }
 
-    let (id, container_id) = (&task_to_stop.id, &task_to_stop.container_id);
+    if task.container_id.is_empty() {
+        let body = ApiError {
+            reason: "not_started".to_string(),
+            message: format!("task {} has no container yet", task_id),
+        };
+        return Err((StatusCode::CONFLICT, Json(body)));
+    }
+
+    let runtime = w.runtime(task.driver).map_err(api_error)?;
+    let logs = runtime.logs(&task.container_id, &options).await;
+    let logs = logs.map_err(|e| api_error(worker::Error::Runtime(e)))?;
+    let body = Body::from_stream(logs.map(|chunk| chunk.map(|c| c.message)));
+    Ok((content_type, body))
+}
+
+/// Control messages of an exec session, sent as text frames. Binary frames
+/// carry stdin one way and the output of the process the other.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExecControl {
+    Resize { rows: u16, cols: u16 },
+}
+
+/// Opens an interactive session in the task's container over a websocket.
+/// The process is started before upgrading, so failing to start it is an
+/// ordinary error response.
+async fn exec_task(
+    State(w): AppState,
+    Path(task_id): Path<Uuid>,
+    Query(options): Query<ExecOptions>,
+    ws: WebSocketUpgrade,
+) -> ApiResult<Response> {
+    let task = {
+        let db = w.db.lock().expect("Failed to lock worker db");
+        db.get(&task_id).cloned()
+    };
+    let task = task.ok_or_else(|| task_not_found(task_id))?;
+    if task.state != task::State::Running {
+        let body = ApiError {
+            reason: "not_running".to_string(),
+            message: format!("task {} is {:?}", task_id, task.state),
+        };
+        return Err((StatusCode::CONFLICT, Json(body)));
+    }
+
+    let runtime = w.runtime(task.driver).map_err(api_error)?;
+    let session = runtime.exec(&task.container_id, &options).await;
+    let session = session.map_err(|e| api_error(worker::Error::Runtime(e)))?;
     info!(
-        "[WORKER] added task {:?} to stop container {:?}",
-        id, container_id
+        "[WORKER] Started exec {:?} in task {:?}: {:?}",
+        session.id, task_id, options.cmd
     );
-    w.add_task(task_to_stop);
+    Ok(ws.on_upgrade(move |socket| exec_session(runtime, socket, session)))
+}
 
+/// Shuttles data between the websocket and the exec session until either the
+/// process exits or the client goes away.
+async fn exec_session(runtime: Arc<dyn ContainerRuntime>, socket: WebSocket, session: ExecSession) {
+    let ExecSession {
+        id,
+        mut output,
+        mut input,
+    } = session;
+    let (mut sender, mut receiver) = socket.split();
+
+    let to_client = async {
+        while let Some(chunk) = output.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    error!("[WORKER] Error reading output of exec {:?}: {}", id, e);
+                    break;
+                }
+            };
+            if sender.send(Message::Binary(chunk.into())).await.is_err() {
+                return;
+            }
+        }
+        let _ = sender.send(Message::Close(None)).await;
+    };
+
+    let from_client = async {
+        while let Some(Ok(msg)) = receiver.next().await {
+            match msg {
+                Message::Binary(data) => {
+                    if input.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Message::Text(text) => match serde_json::from_str(&text) {
+                    Ok(ExecControl::Resize { rows, cols }) => {
+                        if let Err(e) = runtime.resize_exec(&id, rows, cols).await {
+                            error!("[WORKER] Error resizing exec {:?}: {}", id, e);
+                        }
+                    }
+                    Err(e) => error!("[WORKER] Bad control message for exec {:?}: {}", id, e),
+                },
+                Message::Close(_) => break,
+                Message::Ping(_) | Message::Pong(_) => (),
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = to_client => (),
+        _ = from_client => (),
+    }
+    info!("[WORKER] Exec {:?} ended", id);
+}
+
+async fn stop_task(State(w): AppState, Path(task_id): Path<Uuid>) -> ApiResult<StatusCode> {
+    let t = w.request_stop(task_id).map_err(api_error)?;
+    info!(
+        "[WORKER] Task {:?} is {:?}, container {:?}",
+        t.id, t.state, t.container_id
+    );
     Ok(StatusCode::NO_CONTENT)
 }
 
-async fn get_stats(State(w): AppState) -> Json<Stats> {
+/// The host's stats plus how much of it the worker's tasks hold.
+#[derive(Debug, Serialize)]
+struct StatsView {
+    #[serde(flatten)]
+    stats: Stats,
+    allocated: Resources,
+    allocatable: Resources,
+}
+
+async fn get_stats(State(w): AppState) -> Json<StatsView> {
     let stats = w.stats.load().as_ref().clone();
     info!("[WORKER] Getting stats {:?}", stats);
-    Json(stats)
+    let allocated = w.capacity.lock().expect("Failed to lock worker capacity");
+    Json(StatsView {
+        allocated: allocated.allocated(),
+        allocatable: w.allocatable(),
+        stats,
+    })
+}
+
+async fn get_ports(State(w): AppState) -> Json<Vec<PortAllocation>> {
+    let ports = w.ports.lock().expect("Failed to lock worker ports");
+    Json(ports.allocations())
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateVolume {
+    name: String,
+}
+
+async fn list_volumes(State(w): AppState) -> ApiResult<Json<Vec<VolumeInfo>>> {
+    let runtime = w.runtime(task::Driver::Docker).map_err(api_error)?;
+    let volumes = runtime.list_volumes().await;
+    let volumes = volumes.map_err(|e| api_error(worker::Error::Runtime(e)))?;
+    Ok(Json(volumes))
+}
+
+async fn create_volume(
+    State(w): AppState,
+    Json(req): Json<CreateVolume>,
+) -> ApiResult<(StatusCode, Json<VolumeInfo>)> {
+    let runtime = w.runtime(task::Driver::Docker).map_err(api_error)?;
+    let volume = runtime.create_volume(&req.name).await;
+    let volume = volume.map_err(|e| api_error(worker::Error::Runtime(e)))?;
+    info!("[WORKER] Created volume {:?}", volume.name);
+    Ok((StatusCode::CREATED, Json(volume)))
+}
+
+async fn delete_volume(State(w): AppState, Path(name): Path<String>) -> ApiResult<StatusCode> {
+    let runtime = w.runtime(task::Driver::Docker).map_err(api_error)?;
+    let res = runtime.remove_volume(&name).await;
+    res.map_err(|e| api_error(worker::Error::Runtime(e)))?;
+    info!("[WORKER] Removed volume {:?}", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::FakeRuntime;
+    use crate::worker::client::{self, Client};
+    use crate::worker::worker::test_config;
+    use tokio_tungstenite::tungstenite;
+
+    fn fake_worker() -> (Arc<Worker>, Arc<FakeRuntime>) {
+        let runtime = Arc::new(FakeRuntime::new());
+        let w = Worker::with_config("test-worker", test_config(), runtime.clone());
+        (Arc::new(w), runtime)
+    }
+
+    /// Serves the worker's api on a free port, returns the address it is at.
+    async fn serve(w: Arc<Worker>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(setup("127.0.0.1", 0, w).serve(listener));
+        address
+    }
+
+    #[tokio::test]
+    async fn test_exec_task() {
+        let (w, runtime) = fake_worker();
+        let t = Task {
+            state: task::State::Scheduled,
+            image: "strm/helloworld-http".to_string(),
+            ..Default::default()
+        };
+        w.add_task(t.clone());
+        w.run_task().await.unwrap();
+        let address = serve(w.clone()).await;
+
+        let client = Client::new(&address);
+        let mut session = client.exec(&t.id, &ExecOptions::default()).await.unwrap();
+        let stdin = tungstenite::Message::binary(b"ls\n".to_vec());
+        session.send(stdin).await.unwrap();
+        let echo = session.next().await.unwrap().unwrap();
+        assert_eq!(echo.into_data().as_ref(), b"ls\n");
+
+        let resize = serde_json::to_string(&ExecControl::Resize {
+            rows: 40,
+            cols: 120,
+        });
+        let resize = tungstenite::Message::text(resize.unwrap());
+        session.send(resize).await.unwrap();
+        session.close(None).await.unwrap();
+        while session.next().await.is_some() {}
+        assert_eq!(runtime.resizes().len(), 1);
+        assert_eq!(runtime.resizes()[0].1, 40);
+
+        let err = client.exec(&Uuid::new_v4(), &ExecOptions::default()).await;
+        let status = match err {
+            Err(client::Error::StatusCodeError(status, _)) => status,
+            _ => panic!("expected an error status"),
+        };
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let (w, _) = fake_worker();
+        let address = serve(w.clone()).await;
+
+        let allocatable = w.allocatable();
+        let t = Task {
+            state: task::State::Scheduled,
+            cpu: allocatable.cpu,
+            ..Default::default()
+        };
+        let event = TaskEvent {
+            task: t.clone(),
+            ..Default::default()
+        };
+        let url = format!("http://{}/tasks", address);
+        let http = reqwest::Client::new();
+        let res = http.post(&url).json(&event).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let event = TaskEvent {
+            task: Task {
+                id: Uuid::new_v4(),
+                ..t
+            },
+            ..Default::default()
+        };
+        let res = http.post(&url).json(&event).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let err: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(err["reason"], "insufficient_cpu");
+
+        let url = format!("http://{}/stats", address);
+        let stats: serde_json::Value = http.get(&url).send().await.unwrap().json().await.unwrap();
+        assert_eq!(stats["allocated"]["cpu"], allocatable.cpu);
+        assert_eq!(stats["allocatable"]["cpu"], allocatable.cpu);
+        assert!(stats["mem_stats"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_task_stats() {
+        let (w, runtime) = fake_worker();
+        let t = Task {
+            state: task::State::Scheduled,
+            ..Default::default()
+        };
+        w.add_task(t.clone());
+        let handle = w.run_task().await.unwrap().unwrap();
+        let stats = ContainerStats {
+            cpu_percent: 42.0,
+            memory_bytes: 1024,
+            network_rx_bytes: 10,
+            ..Default::default()
+        };
+        runtime.set_stats(&handle.id, stats);
+        w.sample_usage().await;
+        let address = serve(w.clone()).await;
+
+        let http = reqwest::Client::new();
+        let url = format!("http://{}/tasks/{}/stats", address, t.id);
+        let res = http.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let stats: ContainerStats = res.json().await.unwrap();
+        assert_eq!(stats.network_rx_bytes, 10);
+
+        let url = format!("http://{}/tasks", address);
+        let tasks: serde_json::Value = http.get(&url).send().await.unwrap().json().await.unwrap();
+        assert_eq!(tasks[0]["usage"]["cpu_percent"], 42.0);
+        assert_eq!(tasks[0]["usage"]["memory_bytes"], 1024);
+        // the manager still reads them as tasks
+        let _: Vec<Task> = serde_json::from_value(tasks).unwrap();
+
+        let url = format!("http://{}/tasks/{}/stats", address, Uuid::new_v4());
+        let res = http.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::stats::Stats;
+use crate::task::Task;
+
+/// Cpu (in cores), memory and disk (in bytes), what a task asks for or what a
+/// worker has to hand out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Resources {
+    pub cpu: f64,
+    pub memory: u64,
+    pub disk: u64,
+}
+
+impl Resources {
+    pub fn of(t: &Task) -> Self {
+        Resources {
+            cpu: t.cpu,
+            memory: t.memory,
+            disk: t.disk,
+        }
+    }
+
+    /// What the host has according to `stats`: all its cores and memory,
+    /// multiplied by `overcommit`, and the size of the disk `data_dir` is on,
+    /// which can't be handed out twice.
+    ///
+    /// These are the host's totals, not what is free right now. Tasks hold what
+    /// they ask for from the moment they are accepted, and what the running
+    /// ones use already shows up as used in `stats`, so taking it off again
+    /// would count them twice. An `overcommit` below 1.0 leaves room for the
+    /// host's own processes.
+    pub fn allocatable(stats: &Stats, overcommit: f64, data_dir: &Path) -> Self {
+        let memory = stats.mem_total_kb().saturating_mul(1024);
+        Resources {
+            cpu: stats.cpu_info.num_cores() as f64 * overcommit,
+            memory: (memory as f64 * overcommit) as u64,
+            disk: stats.disk_for(data_dir).map_or(0, |disk| disk.total),
+        }
+    }
+
+    fn add(self, other: Resources) -> Self {
+        Resources {
+            cpu: self.cpu + other.cpu,
+            memory: self.memory.saturating_add(other.memory),
+            disk: self.disk.saturating_add(other.disk),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resource {
+    Cpu,
+    Memory,
+    Disk,
+}
+
+/// A task asked for more of `resource` than the worker has left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapacityError {
+    pub resource: Resource,
+    pub requested: f64,
+    pub free: f64,
+}
+
+impl CapacityError {
+    /// What the api reports, "insufficient_memory" and the like.
+    pub fn reason(&self) -> &'static str {
+        match self.resource {
+            Resource::Cpu => "insufficient_cpu",
+            Resource::Memory => "insufficient_memory",
+            Resource::Disk => "insufficient_disk",
+        }
+    }
+}
+
+impl Display for CapacityError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "not enough {:?}: {} requested, {} free",
+            self.resource, self.requested, self.free
+        )
+    }
+}
+
+/// Keeps track of the resources held by the tasks of a worker, from the moment
+/// they are accepted until they are done, the same way `PortAllocator` does
+/// for host ports.
+#[derive(Debug, Default)]
+pub struct Capacity {
+    held: HashMap<Uuid, Resources>,
+}
+
+impl Capacity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserves what the task asks for if it fits in `allocatable` next to the
+    /// other tasks. Reserving again for the same task replaces its previous
+    /// reservation.
+    pub fn reserve(&mut self, t: &Task, allocatable: Resources) -> Result<(), CapacityError> {
+        let requested = Resources::of(t);
+        let others = self
+            .held
+            .iter()
+            .filter(|(id, _)| **id != t.id)
+            .fold(Resources::default(), |sum, (_, r)| sum.add(*r));
+
+        let checks = [
+            (Resource::Cpu, requested.cpu, allocatable.cpu - others.cpu),
+            (
+                Resource::Memory,
+                requested.memory as f64,
+                allocatable.memory as f64 - others.memory as f64,
+            ),
+            (
+                Resource::Disk,
+                requested.disk as f64,
+                allocatable.disk as f64 - others.disk as f64,
+            ),
+        ];
+        // nothing requested always fits, even on a worker that is already full
+        let short = checks
+            .into_iter()
+            .find(|(_, requested, free)| *requested > 0.0 && requested > free);
+        if let Some((resource, requested, free)) = short {
+            let free = free.max(0.0);
+            return Err(CapacityError {
+                resource,
+                requested,
+                free,
+            });
+        }
+
+        self.held.insert(t.id, requested);
+        Ok(())
+    }
+
+    /// Counts what a task that is already running holds, whether it fits or
+    /// not.
+    pub fn hold(&mut self, t: &Task) {
+        self.held.insert(t.id, Resources::of(t));
+    }
+
+    pub fn release(&mut self, task_id: Uuid) {
+        self.held.remove(&task_id);
+    }
+
+    pub fn allocated(&self) -> Resources {
+        self.held
+            .values()
+            .fold(Resources::default(), |sum, r| sum.add(*r))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::worker::stats::{get_stats, DiskStats};
+
+    fn task(cpu: f64, memory: u64) -> Task {
+        Task {
+            cpu,
+            memory,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reserve() {
+        let allocatable = Resources {
+            cpu: 2.0,
+            memory: 1024,
+            disk: 0,
+        };
+        let mut capacity = Capacity::new();
+
+        let web = task(1.5, 512);
+        capacity.reserve(&web, allocatable).unwrap();
+        // again for the same task doesn't count twice
+        capacity.reserve(&web, allocatable).unwrap();
+
+        let err = capacity.reserve(&task(1.0, 256), allocatable).unwrap_err();
+        assert_eq!(err.resource, Resource::Cpu);
+        assert_eq!(err.free, 0.5);
+        assert_eq!(err.reason(), "insufficient_cpu");
+        let err = capacity.reserve(&task(0.5, 768), allocatable).unwrap_err();
+        assert_eq!(err.resource, Resource::Memory);
+        let other = task(0.5, 512);
+        capacity.reserve(&other, allocatable).unwrap();
+        capacity.reserve(&task(0.0, 0), allocatable).unwrap();
+        assert_eq!(
+            capacity.allocated(),
+            Resources {
+                cpu: 2.0,
+                memory: 1024,
+                disk: 0
+            }
+        );
+
+        capacity.release(web.id);
+        capacity.reserve(&task(1.0, 256), allocatable).unwrap();
+    }
+
+    #[test]
+    fn test_allocatable_disk() {
+        let disk = |mount_point: &str, total| DiskStats {
+            total,
+            free: total,
+            mount_point: mount_point.to_string(),
+            file_system: "ext4".to_string(),
+        };
+        let mut stats = get_stats();
+        stats.disk_stats = vec![
+            disk("/", 100),
+            disk("/var/lib/docker", 40),
+            disk("/home", 1000),
+        ];
+
+        let allocatable = |data_dir| Resources::allocatable(&stats, 1.0, Path::new(data_dir));
+        assert_eq!(allocatable("/var/lib/docker").disk, 40);
+        assert_eq!(allocatable("/var/lib/docker/overlay2").disk, 40);
+        // on the root filesystem, like most hosts
+        assert_eq!(allocatable("/srv/docker").disk, 100);
+        stats.disk_stats.clear();
+        assert_eq!(
+            Resources::allocatable(&stats, 1.0, Path::new("/var/lib/docker")).disk,
+            0
+        );
+    }
+}
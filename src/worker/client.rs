@@ -1,5 +1,13 @@
-use crate::task::{Task, TaskEvent};
+use super::api::ApiError;
+use super::ports::PortAllocation;
+use crate::task::{ExecOptions, LogOptions, Task, TaskEvent};
+use axum::body::Bytes;
+use futures::Stream;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tracing::info;
+use uuid::Uuid;
 
 pub struct Client {
     client: reqwest::Client,
@@ -11,10 +19,24 @@ pub enum Error {
     ErrorReachingWorker(reqwest::Error),
     StatusCodeError(reqwest::StatusCode, String),
     ErrorDecodingResponse(String),
+    ErrorOpeningWebSocket(String),
 }
 
+pub type ExecStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
 type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// The `reason` the worker gave for refusing the request, see `ApiError`.
+    pub fn reason(&self) -> Option<String> {
+        let Error::StatusCodeError(_, body) = self else {
+            return None;
+        };
+        let body = serde_json::from_str::<ApiError>(body).ok()?;
+        Some(body.reason)
+    }
+}
+
 impl Client {
     pub fn new(worker: &str) -> Self {
         Client {
@@ -39,9 +61,8 @@ impl Client {
         let res = res.unwrap();
         if !res.status().is_success() {
             let status = res.status();
-            let err = res.text().await;
-            let err_str = format!("{:?}", err);
-            return Err(Error::StatusCodeError(status, err_str));
+            let err = res.text().await.unwrap_or_default();
+            return Err(Error::StatusCodeError(status, err));
         }
         let task = res.json::<Task>().await;
         if task.is_err() {
@@ -52,4 +73,83 @@ impl Client {
         let task = task.unwrap();
         Ok(task)
     }
+
+    pub async fn stop_task(&self, task_id: &Uuid) -> Result<()> {
+        let url = format!("http://{}/tasks/{}", self.worker, task_id);
+        let res = self
+            .client
+            .delete(&url)
+            .send()
+            .await
+            .map_err(Error::ErrorReachingWorker)?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let err = res.text().await;
+            return Err(Error::StatusCodeError(status, format!("{:?}", err)));
+        }
+        Ok(())
+    }
+
+    pub async fn get_ports(&self) -> Result<Vec<PortAllocation>> {
+        let url = format!("http://{}/ports", self.worker);
+        let res = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(Error::ErrorReachingWorker)?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let err = res.text().await;
+            return Err(Error::StatusCodeError(status, format!("{:?}", err)));
+        }
+        res.json::<Vec<PortAllocation>>()
+            .await
+            .map_err(|e| Error::ErrorDecodingResponse(format!("{:?}", e)))
+    }
+
+    /// Opens the worker's log stream for a task, the body is passed on as it
+    /// arrives.
+    pub async fn get_logs(
+        &self,
+        task_id: &Uuid,
+        options: &LogOptions,
+    ) -> Result<impl Stream<Item = reqwest::Result<Bytes>>> {
+        let url = format!("http://{}/tasks/{}/logs", self.worker, task_id);
+        let res = self
+            .client
+            .get(&url)
+            .query(options)
+            .send()
+            .await
+            .map_err(Error::ErrorReachingWorker)?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let err = res.text().await;
+            return Err(Error::StatusCodeError(status, format!("{:?}", err)));
+        }
+        Ok(res.bytes_stream())
+    }
+
+    /// Opens an exec session on the worker, see the worker's exec route for
+    /// what goes over it.
+    pub async fn exec(&self, task_id: &Uuid, options: &ExecOptions) -> Result<ExecStream> {
+        let url = format!("ws://{}/tasks/{}/exec", self.worker, task_id);
+        let mut url =
+            reqwest::Url::parse(&url).map_err(|e| Error::ErrorOpeningWebSocket(e.to_string()))?;
+        url.query_pairs_mut()
+            .append_pair("cmd", &options.cmd)
+            .append_pair("tty", &options.tty.to_string());
+
+        match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((stream, _)) => Ok(stream),
+            Err(tungstenite::Error::Http(res)) => {
+                let status = res.status();
+                let body = res.body().as_deref().unwrap_or_default();
+                let err = String::from_utf8_lossy(body).to_string();
+                Err(Error::StatusCodeError(status, err))
+            }
+            Err(e) => Err(Error::ErrorOpeningWebSocket(e.to_string())),
+        }
+    }
 }
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use tokio::net::TcpStream;
+
+use crate::task::{
+    ContainerRuntime, Driver, ExecOptions, ExecSession, Port, Probe, ProbeAction, ProbeStatus, Task,
+};
+
+/// Whether the worker can run `action` against tasks of `driver`. Processes
+/// have no exec, and wasm modules no network either.
+pub fn supports(driver: Driver, action: &ProbeAction) -> bool {
+    match (driver, action) {
+        (Driver::Docker, _) => true,
+        (Driver::Process, ProbeAction::Exec { .. }) => false,
+        (Driver::Process, _) => true,
+        (Driver::Wasm, _) => false,
+    }
+}
+
+/// Whether `probe` should run again. The first run is one interval after the
+/// task started, so the container gets a moment to come up.
+pub fn is_due(probe: &Probe, status: &ProbeStatus, start_time: DateTime<Utc>) -> bool {
+    let last = status.last_check.unwrap_or(start_time);
+    let interval = chrono::Duration::seconds(probe.interval_secs as i64);
+    Utc::now() >= last + interval
+}
+
+/// Runs `probe`, if there is one and it is due. `None` if it didn't run.
+pub async fn run_if_due(
+    runtime: &dyn ContainerRuntime,
+    t: &Task,
+    probe: &Option<Probe>,
+    status: &ProbeStatus,
+) -> Option<Result<(), String>> {
+    match probe {
+        Some(p) if is_due(p, status, t.start_time) => Some(run_probe(runtime, t, p).await),
+        _ => None,
+    }
+}
+
+/// Runs `probe` once against the task's container. The error says why it
+/// failed.
+pub async fn run_probe(
+    runtime: &dyn ContainerRuntime,
+    t: &Task,
+    probe: &Probe,
+) -> Result<(), String> {
+    let timeout = Duration::from_secs(probe.timeout_secs);
+    match tokio::time::timeout(timeout, check(runtime, t, &probe.action)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}s", probe.timeout_secs)),
+    }
+}
+
+async fn check(
+    runtime: &dyn ContainerRuntime,
+    t: &Task,
+    action: &ProbeAction,
+) -> Result<(), String> {
+    match action {
+        ProbeAction::Http { port, path } => {
+            let url = format!("http://{}{}", host_address(t, port)?, path);
+            let res = reqwest::get(&url).await.map_err(|e| e.to_string())?;
+            let status = res.status();
+            if status.is_success() || status.is_redirection() {
+                Ok(())
+            } else {
+                Err(format!("GET {} returned {}", url, status))
+            }
+        }
+        ProbeAction::Tcp { port } => {
+            let address = host_address(t, port)?;
+            match TcpStream::connect(&address).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("connecting to {}: {}", address, e)),
+            }
+        }
+        ProbeAction::Exec { cmd } => {
+            let options = ExecOptions {
+                cmd: cmd.clone(),
+                tty: false,
+            };
+            let session = runtime.exec(&t.container_id, &options).await;
+            let ExecSession { id, output, input } = session.map_err(|e| e.to_string())?;
+            // nothing to say on stdin, the output ends when the command exits
+            drop(input);
+            output.for_each(|_| async {}).await;
+            match runtime
+                .exec_exit_code(&id)
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                Some(0) => Ok(()),
+                Some(code) => Err(format!("{:?} exited with {}", cmd, code)),
+                None => Err(format!("{:?} is still running", cmd)),
+            }
+        }
+    }
+}
+
+/// Where the worker reaches a container port: the host port it is published
+/// on, on localhost unless it is bound to a specific address. Processes listen
+/// on the host's network themselves.
+fn host_address(t: &Task, port: &Port) -> Result<String, String> {
+    if t.driver == Driver::Process {
+        return Ok(format!("127.0.0.1:{}", port.number));
+    }
+    let (key, _) = port.to_docker_repr();
+    let binding = t
+        .host_ports
+        .get(&key)
+        .ok_or_else(|| format!("port {} is not published", key))?;
+    let address = match binding.host_ip.as_str() {
+        "" | "0.0.0.0" | "::" => format!("127.0.0.1:{}", binding.host_port),
+        ip if ip.contains(':') => format!("[{}]:{}", ip, binding.host_port),
+        ip => format!("{}:{}", ip, binding.host_port),
+    };
+    Ok(address)
+}
@@ -0,0 +1,168 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use crate::task::{LogChunk, LogOptions};
+
+/// Keeps the logs of tasks whose container is gone, one file per task with a
+/// `LogChunk` as json on every line.
+///
+/// A file that grows past `max_bytes` is rotated to `<task>.log.1`, replacing
+/// the previous one, so a task never takes more than twice that. Files not
+/// written to for `max_age` are deleted by `prune`.
+#[derive(Debug)]
+pub struct LogStore {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+}
+
+impl LogStore {
+    pub fn new(dir: PathBuf, max_bytes: u64, max_age: Duration) -> Self {
+        LogStore {
+            dir,
+            max_bytes,
+            max_age,
+        }
+    }
+
+    fn path(&self, task_id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.log", task_id))
+    }
+
+    fn rotated_path(&self, task_id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.log.1", task_id))
+    }
+
+    pub fn append(&self, task_id: Uuid, chunks: &[LogChunk]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(task_id);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut size = file.metadata()?.len();
+
+        for chunk in chunks {
+            let mut line = serde_json::to_vec(chunk)?;
+            line.push(b'\n');
+            if size > 0 && size + line.len() as u64 > self.max_bytes {
+                fs::rename(&path, self.rotated_path(task_id))?;
+                file = File::create(&path)?;
+                size = 0;
+            }
+            file.write_all(&line)?;
+            size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// The stored logs of a task, oldest first, or `None` if there are none.
+    /// `follow` has no meaning here and is ignored.
+    pub fn read(&self, task_id: Uuid, options: &LogOptions) -> io::Result<Option<Vec<LogChunk>>> {
+        let mut chunks = vec![];
+        let mut found = false;
+        for path in [self.rotated_path(task_id), self.path(task_id)] {
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            found = true;
+            for line in BufReader::new(file).lines() {
+                let chunk: LogChunk = serde_json::from_str(&line?)?;
+                if options.matches(&chunk) {
+                    chunks.push(chunk);
+                }
+            }
+        }
+        if !found {
+            return Ok(None);
+        }
+
+        if let Some(tail) = options.tail {
+            chunks = chunks.split_off(chunks.len().saturating_sub(tail as usize));
+        }
+        Ok(Some(chunks))
+    }
+
+    /// Deletes the files older than `max_age`, returns how many.
+    pub fn prune(&self) -> io::Result<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            if age > self.max_age {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::LogSource;
+
+    fn chunk(source: LogSource, message: &str) -> LogChunk {
+        LogChunk {
+            source,
+            timestamp: None,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_log_store() {
+        let dir = std::env::temp_dir().join(format!("cube-logs-{}", Uuid::new_v4()));
+        let task_id = Uuid::new_v4();
+        let line = serde_json::to_vec(&chunk(LogSource::Stdout, "line 0\n")).unwrap();
+        // room for two lines per file, newlines included
+        let max_bytes = 2 * (line.len() as u64 + 1);
+        let store = LogStore::new(dir.clone(), max_bytes, Duration::from_secs(60));
+        assert_eq!(store.read(task_id, &LogOptions::default()).unwrap(), None);
+
+        let chunks: Vec<_> = (0..5)
+            .map(|i| chunk(LogSource::Stdout, &format!("line {}\n", i)))
+            .collect();
+        store.append(task_id, &chunks).unwrap();
+        store
+            .append(task_id, &[chunk(LogSource::Stderr, "line 5\n")])
+            .unwrap();
+
+        // line 0 and 1 were rotated out
+        let all = store
+            .read(task_id, &LogOptions::default())
+            .unwrap()
+            .unwrap();
+        let messages: Vec<_> = all.iter().map(|c| c.message.as_str()).collect();
+        assert_eq!(messages, ["line 2\n", "line 3\n", "line 4\n", "line 5\n"]);
+
+        let last_stdout = LogOptions {
+            tail: Some(1),
+            stderr: false,
+            ..Default::default()
+        };
+        let last = store.read(task_id, &last_stdout).unwrap().unwrap();
+        assert_eq!(last, vec![chunks[4].clone()]);
+
+        assert_eq!(store.prune().unwrap(), 0);
+        let store = LogStore::new(dir.clone(), 1024, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(store.prune().unwrap(), 2);
+        assert_eq!(store.read(task_id, &LogOptions::default()).unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
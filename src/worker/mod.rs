@@ -1,9 +1,15 @@
 pub mod api;
+pub mod capacity;
 pub mod client;
+pub mod health;
+pub mod logs;
+pub mod ports;
 pub mod stats;
+pub mod store;
+#[allow(clippy::module_inception)]
 pub mod worker;
 
 pub use api::start_api;
 pub use client::Client;
-pub use worker::{collect_stats, Worker};
-
+pub use store::{MemoryStore, TaskStore, WalStore};
+pub use worker::{collect_stats, prune_logs_loop, Adoption, Config, Worker};
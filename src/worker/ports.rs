@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::task::{Port, Task};
+
+/// A host port held by a task on this worker.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortAllocation {
+    pub port: Port,
+    pub task_id: Uuid,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PortError {
+    // the port is already held by `task_id`
+    Conflict { port: Port, task_id: Uuid },
+    Exhausted,
+    InvalidPort(String),
+}
+
+impl Display for PortError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PortError::Conflict { port, task_id } => write!(
+                f,
+                "host port {}/{} is already used by task {}",
+                port.number, port.protocol, task_id
+            ),
+            PortError::Exhausted => write!(f, "no free host ports left in the dynamic range"),
+            PortError::InvalidPort(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Keeps track of the host ports used by the tasks of a worker, so two tasks
+/// asking for the same port are caught before docker refuses to start one of
+/// them. Bindings without a host port get one from `range`.
+#[derive(Debug)]
+pub struct PortAllocator {
+    range: RangeInclusive<u16>,
+    held: HashMap<Port, Uuid>,
+}
+
+impl PortAllocator {
+    pub fn new(range: RangeInclusive<u16>) -> Self {
+        PortAllocator {
+            range,
+            held: HashMap::new(),
+        }
+    }
+
+    /// Reserves every host port in `t.port_bindings`, filling in the dynamic
+    /// ones. Nothing is reserved if any of them can't be. Allocating again for
+    /// the same task replaces its previous reservation.
+    pub fn allocate(&mut self, t: &mut Task) -> Result<(), PortError> {
+        let mut wanted: HashMap<Port, String> = HashMap::new();
+        let mut dynamic = vec![];
+
+        for (container_port, binding) in &t.port_bindings {
+            let protocol = container_port
+                .parse::<Port>()
+                .map_err(PortError::InvalidPort)?
+                .protocol;
+            if binding.host_port == 0 {
+                dynamic.push((container_port.clone(), protocol));
+                continue;
+            }
+
+            let port = Port {
+                number: binding.host_port,
+                protocol,
+            };
+            match self.held.get(&port) {
+                Some(owner) if *owner != t.id => {
+                    return Err(PortError::Conflict {
+                        port,
+                        task_id: *owner,
+                    })
+                }
+                _ => (),
+            }
+            if wanted.insert(port, container_port.clone()).is_some() {
+                return Err(PortError::Conflict {
+                    port,
+                    task_id: t.id,
+                });
+            }
+        }
+
+        for (container_port, protocol) in dynamic {
+            let port = self
+                .range
+                .clone()
+                .map(|number| Port { number, protocol })
+                .find(|p| !self.held.contains_key(p) && !wanted.contains_key(p))
+                .ok_or(PortError::Exhausted)?;
+            wanted.insert(port, container_port);
+        }
+
+        self.release(t.id);
+        for (port, container_port) in wanted {
+            self.held.insert(port, t.id);
+            if let Some(binding) = t.port_bindings.get_mut(&container_port) {
+                binding.host_port = port.number;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn release(&mut self, task_id: Uuid) {
+        self.held.retain(|_, owner| *owner != task_id);
+    }
+
+    pub fn allocations(&self) -> Vec<PortAllocation> {
+        self.held
+            .iter()
+            .map(|(port, task_id)| PortAllocation {
+                port: *port,
+                task_id: *task_id,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{PortBinding, Protocol};
+
+    fn task_with_ports(ports: &[(&str, u16)]) -> Task {
+        let port_bindings = ports
+            .iter()
+            .map(|(container_port, host_port)| {
+                let binding = PortBinding {
+                    host_port: *host_port,
+                    ..Default::default()
+                };
+                (container_port.to_string(), binding)
+            })
+            .collect();
+        Task {
+            port_bindings,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_allocate() {
+        let mut ports = PortAllocator::new(20000..=20001);
+
+        let mut web = task_with_ports(&[("80/tcp", 8080), ("443/tcp", 0)]);
+        ports.allocate(&mut web).unwrap();
+        assert_eq!(web.port_bindings["443/tcp"].host_port, 20000);
+
+        // same port number, other protocol
+        let mut dns = task_with_ports(&[("53/udp", 8080)]);
+        ports.allocate(&mut dns).unwrap();
+
+        let mut other = task_with_ports(&[("80/tcp", 8080), ("443/tcp", 0)]);
+        let err = ports.allocate(&mut other).unwrap_err();
+        let port = Port {
+            number: 8080,
+            protocol: Protocol::Tcp,
+        };
+        assert_eq!(
+            err,
+            PortError::Conflict {
+                port,
+                task_id: web.id
+            }
+        );
+        // a failed allocation must not hold on to anything
+        assert_eq!(ports.allocations().len(), 3);
+
+        let mut dynamic = task_with_ports(&[("80/tcp", 0), ("81/tcp", 0)]);
+        assert_eq!(ports.allocate(&mut dynamic), Err(PortError::Exhausted));
+
+        ports.release(web.id);
+        ports.allocate(&mut other).unwrap();
+        assert_eq!(ports.allocations().len(), 3);
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use procfs::prelude::*;
 use procfs::{CpuInfo, CpuPressure, CpuTime, KernelStats, LoadAverage, LocalSystemInfo, Meminfo};
 use serde::Serialize;
@@ -65,6 +67,14 @@ impl Stats {
         self.disk_total_bytes() - self.disk_free_bytes()
     }
 
+    /// The disk `path` is on, the one mounted closest to it.
+    pub fn disk_for(&self, path: &Path) -> Option<&DiskStats> {
+        self.disk_stats
+            .iter()
+            .filter(|disk| path.starts_with(&disk.mount_point))
+            .max_by_key(|disk| disk.mount_point.len())
+    }
+
     // CPU stats
     pub fn cpu_usage(&self) -> f32 {
         let d = &self.cpu_time;
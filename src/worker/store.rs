@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use uuid::Uuid;
+
+use crate::task::Task;
+
+// puts after which `WalStore` rewrites its log with only the latest versions
+const COMPACT_AFTER: usize = 1000;
+
+/// Where a worker keeps its tasks, so they survive the worker restarting. The
+/// worker works on its own copy in `Worker::db` and puts every change here.
+pub trait TaskStore: Debug + Send + Sync {
+    /// Records the latest version of the task, durably once it returns.
+    fn put(&self, t: &Task) -> io::Result<()>;
+    /// The latest version of every task put so far.
+    fn load(&self) -> io::Result<Vec<Task>>;
+}
+
+/// Keeps nothing across restarts, for workers that don't need to.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    tasks: Mutex<HashMap<Uuid, Task>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TaskStore for MemoryStore {
+    fn put(&self, t: &Task) -> io::Result<()> {
+        self.tasks.lock().unwrap().insert(t.id, t.clone());
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Vec<Task>> {
+        Ok(self.tasks.lock().unwrap().values().cloned().collect())
+    }
+}
+
+/// Appends every version of a task to a log file, as json on its own line,
+/// and syncs it before `put` returns. Loading replays the log, the last version
+/// of a task wins. Every `COMPACT_AFTER` puts the log is rewritten with only
+/// the latest versions, into a new file that then replaces it.
+#[derive(Debug)]
+pub struct WalStore {
+    path: PathBuf,
+    // puts since the log was last compacted
+    writes: Mutex<usize>,
+}
+
+impl WalStore {
+    pub fn new(path: PathBuf) -> Self {
+        WalStore {
+            path,
+            writes: Mutex::new(0),
+        }
+    }
+
+    /// The tasks in the log, and whether it ends in half a line.
+    fn replay(&self) -> io::Result<(HashMap<Uuid, Task>, bool)> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((HashMap::new(), false)),
+            Err(e) => return Err(e),
+        };
+        let mut tasks = HashMap::new();
+        let mut lines = BufReader::new(file).lines().peekable();
+        while let Some(line) = lines.next() {
+            match serde_json::from_str::<Task>(&line?) {
+                Ok(t) => {
+                    tasks.insert(t.id, t);
+                }
+                // a crash in the middle of a put leaves half a line at the end
+                Err(_) if lines.peek().is_none() => return Ok((tasks, true)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok((tasks, false))
+    }
+
+    fn compact(&self) -> io::Result<()> {
+        let (tasks, _) = self.replay()?;
+        self.rewrite(&tasks)
+    }
+
+    fn rewrite(&self, tasks: &HashMap<Uuid, Task>) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for t in tasks.values() {
+            let mut line = serde_json::to_vec(t)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+impl TaskStore for WalStore {
+    fn put(&self, t: &Task) -> io::Result<()> {
+        let mut writes = self.writes.lock().unwrap();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut line = serde_json::to_vec(t)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        *writes += 1;
+        if *writes >= COMPACT_AFTER {
+            self.compact()?;
+            *writes = 0;
+        }
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<Vec<Task>> {
+        let _writes = self.writes.lock().unwrap();
+        let (tasks, torn) = self.replay()?;
+        // the next put would end up on the same line
+        if torn {
+            self.rewrite(&tasks)?;
+        }
+        Ok(tasks.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::State;
+
+    fn store() -> WalStore {
+        let dir = std::env::temp_dir().join(format!("cube-store-{}", Uuid::new_v4()));
+        WalStore::new(dir.join("tasks.wal"))
+    }
+
+    #[test]
+    fn test_wal_store() {
+        let store = store();
+        assert!(store.load().unwrap().is_empty());
+
+        let mut t = Task {
+            name: "task".to_string(),
+            state: State::Scheduled,
+            ..Default::default()
+        };
+        let other = Task::default();
+        store.put(&t).unwrap();
+        store.put(&other).unwrap();
+        t.state = State::Running;
+        store.put(&t).unwrap();
+
+        // what a restarted worker would see
+        let reopened = WalStore::new(store.path.clone());
+        let mut tasks = reopened.load().unwrap();
+        tasks.sort_by_key(|t| t.name.clone());
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[1].id, t.id);
+        assert_eq!(tasks[1].state, State::Running);
+
+        // a put cut short by a crash
+        let mut file = OpenOptions::new().append(true).open(&store.path).unwrap();
+        file.write_all(b"{\"id\": \"").unwrap();
+        assert_eq!(reopened.load().unwrap().len(), 2);
+        reopened.put(&Task::default()).unwrap();
+        assert_eq!(reopened.load().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_wal_store_compacts() {
+        let store = store();
+        let mut t = Task::default();
+        for attempt in 0..COMPACT_AFTER as u32 + 1 {
+            t.attempts = attempt;
+            store.put(&t).unwrap();
+        }
+        let log = fs::read_to_string(&store.path).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        let tasks = store.load().unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].attempts, COMPACT_AFTER as u32);
+    }
+}
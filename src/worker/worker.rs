@@ -1,14 +1,118 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use arc_swap::ArcSwap;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use tokio::sync::{Notify, Semaphore};
 use tracing::{error, info};
 use uuid::Uuid;
 
+use super::capacity::{Capacity, CapacityError, Resources};
+use super::health;
+use super::logs::LogStore;
+use super::ports::{PortAllocator, PortError};
 use super::stats::{self, Stats};
-use crate::task::{self, Task};
+use super::store::{MemoryStore, TaskStore};
+use crate::task::{
+    self, Actor, ContainerHandle, ContainerRuntime, ContainerStats, ContainerSummary,
+    FailureReason, InvalidTransition, LogOptions, PullProgress, RegistryAuth, RuntimeError,
+    StopOutcome, Task,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    TaskNotFound(Uuid),
+    InvalidTransition(task::State, task::State),
+    Runtime(RuntimeError),
+    Ports(PortError),
+    DriverUnavailable(task::Driver),
+    Capacity(CapacityError),
+    NotRunning(Uuid),
+    UnsupportedProbe(task::Driver),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::TaskNotFound(id) => write!(f, "task {} not found", id),
+            Error::InvalidTransition(from, to) => {
+                write!(f, "invalid state transition from {:?} to {:?}", from, to)
+            }
+            Error::Runtime(e) => write!(f, "{}", e),
+            Error::Ports(e) => write!(f, "{}", e),
+            Error::DriverUnavailable(d) => write!(f, "no runtime for the {:?} driver", d),
+            Error::Capacity(e) => write!(f, "{}", e),
+            Error::NotRunning(id) => write!(f, "task {} is not running", id),
+            Error::UnsupportedProbe(d) => {
+                write!(f, "the {:?} driver can't run the task's probes", d)
+            }
+        }
+    }
+}
+
+impl From<InvalidTransition> for Error {
+    fn from(e: InvalidTransition) -> Self {
+        Error::InvalidTransition(e.from, e.to)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // host ports handed out to bindings that don't ask for a specific one
+    pub port_range: RangeInclusive<u16>,
+    // credentials for private registries, by registry host ("ghcr.io")
+    pub registry_auth: HashMap<String, RegistryAuth>,
+    // where the logs of removed containers are kept, see `LogStore`
+    pub log_dir: PathBuf,
+    pub log_max_bytes: u64,
+    pub log_max_age: Duration,
+    // wait before restarting a task, doubled with every restart up to the max
+    pub restart_backoff: Duration,
+    pub restart_backoff_max: Duration,
+    // remove the containers `adopt_containers` finds no task for, instead of
+    // only reporting them
+    pub remove_orphans: bool,
+    // how many tasks `run_tasks_loop` starts or stops at once
+    pub max_concurrent_tasks: usize,
+    // how many times its cpus and memory the worker hands out to tasks
+    pub overcommit: f64,
+    // where docker keeps images and containers, the tasks' `disk` comes out
+    // of the filesystem it is on
+    pub data_dir: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            // stays clear of the range docker itself uses for ephemeral ports
+            port_range: 20000..=29999,
+            registry_auth: HashMap::new(),
+            log_dir: std::env::temp_dir().join("cube-logs"),
+            log_max_bytes: 10 * 1024 * 1024,
+            log_max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            restart_backoff: Duration::from_secs(1),
+            restart_backoff_max: Duration::from_secs(5 * 60),
+            remove_orphans: false,
+            max_concurrent_tasks: 4,
+            overcommit: 1.0,
+            data_dir: PathBuf::from("/var/lib/docker"),
+        }
+    }
+}
+
+/// What `Worker::adopt_containers` found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Adoption {
+    // tasks taken back into `db`
+    pub adopted: Vec<Uuid>,
+    // ids of the labeled containers no task could be rebuilt for
+    pub orphans: Vec<String>,
+}
 
 #[derive(Debug)]
 pub struct Worker {
@@ -18,114 +122,921 @@ pub struct Worker {
     pub db: Mutex<HashMap<Uuid, Task>>,
     pub stats: ArcSwap<Stats>,
     pub task_count: u64,
+    // what runs the tasks of each driver
+    pub runtimes: HashMap<task::Driver, Arc<dyn ContainerRuntime>>,
+    pub ports: Mutex<PortAllocator>,
+    // cpu, memory and disk held by the tasks, see `allocatable`
+    pub capacity: Mutex<Capacity>,
+    // the latest usage sample of each running task
+    pub usage: Mutex<HashMap<Uuid, ContainerStats>>,
+    // image pulls in progress, by task
+    pub pulls: Mutex<HashMap<Uuid, PullProgress>>,
+    // logs of tasks whose container was removed
+    pub logs: LogStore,
+    // tasks waiting out their backoff before being queued again
+    pub restarts: Mutex<Vec<(DateTime<Utc>, Task)>>,
+    // where every change to `db` goes, see `load_tasks`
+    store: Arc<dyn TaskStore>,
+    // wakes `run_tasks_loop` when a task is queued or one it ran is done
+    queued: Notify,
+    // tasks being started or stopped, what comes next for them waits its turn
+    busy: Mutex<HashSet<Uuid>>,
+    // one per task `run_tasks_loop` can run at once
+    slots: Arc<Semaphore>,
+    registry_auth: HashMap<String, RegistryAuth>,
+    restart_backoff: Duration,
+    restart_backoff_max: Duration,
+    remove_orphans: bool,
+    overcommit: f64,
+    data_dir: PathBuf,
 }
 
+// how long a task waits before it is tried again after the runtime couldn't
+// be reached
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Runs the queued tasks as they come in, up to `max_concurrent_tasks` at
+/// once. What is queued for the same task runs in order, one at a time, so a
+/// stop never overtakes the start before it.
 pub async fn run_tasks_loop(worker: Arc<Worker>) {
-    let delay = Duration::from_secs(10);
     loop {
-        info!("Sleeping for {} seconds", delay.as_secs());
+        worker.queue_due_restarts();
+        let slot = worker.slots.clone().acquire_owned().await;
+        let slot = slot.expect("the semaphore is never closed");
+        let Some(t) = worker.next_task() else {
+            drop(slot);
+            // restarts are the only thing that comes due without a notification
+            let wait = worker.next_restart_in().unwrap_or(RETRY_DELAY);
+            let _ = tokio::time::timeout(wait, worker.queued.notified()).await;
+            continue;
+        };
+
+        let worker = worker.clone();
+        tokio::spawn(async move {
+            let id = t.id;
+            let result = worker.execute(t).await;
+            drop(slot);
+            if let Err(e) = result {
+                error!("[WORKER] Error running task {:?}: {}", id, e);
+                // it was queued again, keep it busy until it is worth a retry
+                if matches!(e, Error::Runtime(ref e) if e.is_retryable()) {
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+            worker.finish(id);
+        });
+    }
+}
+
+/// Periodically checks on running containers so tasks whose process exited on
+/// its own end up `Completed` or `Failed` instead of `Running` forever.
+pub async fn inspect_tasks_loop(worker: Arc<Worker>) {
+    let delay = Duration::from_secs(15);
+    loop {
+        info!("[WORKER] Checking running tasks");
+        worker.inspect_tasks().await;
         tokio::time::sleep(delay).await;
+    }
+}
 
-        let len = worker.queue.lock().unwrap().len();
+/// Runs the health probes of running tasks as they come due.
+pub async fn health_checks_loop(worker: Arc<Worker>) {
+    let delay = Duration::from_secs(1);
+    loop {
+        worker.check_health().await;
+        tokio::time::sleep(delay).await;
+    }
+}
 
-        if len == 0 {
-            info!("No tasks in queue");
-            continue;
-        }
+/// Samples what the containers of running tasks use.
+pub async fn sample_usage_loop(worker: Arc<Worker>) {
+    let delay = Duration::from_secs(10);
+    loop {
+        worker.sample_usage().await;
+        tokio::time::sleep(delay).await;
+    }
+}
 
-        let result = worker.run_task().await;
-        if result.error.is_some() {
-            info!("Error Running task: {}", result.error.unwrap());
+/// Deletes stored logs once they are older than the configured age.
+pub async fn prune_logs_loop(worker: Arc<Worker>) {
+    let delay = Duration::from_secs(60 * 60);
+    loop {
+        match worker.logs.prune() {
+            Ok(n) => info!("[WORKER] Pruned {} old log files", n),
+            Err(e) => error!("[WORKER] Error pruning logs: {}", e),
         }
+        tokio::time::sleep(delay).await;
     }
 }
 
 impl Worker {
-    pub fn new(name: &str) -> Worker {
+    pub fn new(name: &str) -> Result<Worker, Error> {
+        let docker = task::new_docker().map_err(Error::Runtime)?;
+        Ok(Self::with_runtime(name, Arc::new(docker)))
+    }
+
+    pub fn with_runtime(name: &str, runtime: Arc<dyn ContainerRuntime>) -> Worker {
+        Self::with_config(name, Config::default(), runtime)
+    }
+
+    pub fn with_config(name: &str, config: Config, runtime: Arc<dyn ContainerRuntime>) -> Worker {
         Worker {
             name: name.to_string(),
             queue: Mutex::new(VecDeque::new()),
             db: Mutex::new(HashMap::new()),
             stats: ArcSwap::new(Arc::new(stats::get_stats())),
             task_count: 0,
+            runtimes: HashMap::from([(task::Driver::Docker, runtime)]),
+            ports: Mutex::new(PortAllocator::new(config.port_range)),
+            capacity: Mutex::new(Capacity::new()),
+            usage: Mutex::new(HashMap::new()),
+            pulls: Mutex::new(HashMap::new()),
+            logs: LogStore::new(config.log_dir, config.log_max_bytes, config.log_max_age),
+            restarts: Mutex::new(vec![]),
+            store: Arc::new(MemoryStore::new()),
+            queued: Notify::new(),
+            busy: Mutex::new(HashSet::new()),
+            slots: Arc::new(Semaphore::new(config.max_concurrent_tasks.max(1))),
+            registry_auth: config.registry_auth,
+            restart_backoff: config.restart_backoff,
+            restart_backoff_max: config.restart_backoff_max,
+            remove_orphans: config.remove_orphans,
+            overcommit: config.overcommit,
+            data_dir: config.data_dir,
+        }
+    }
+
+    /// Runs the tasks that ask for `driver` with `runtime`.
+    pub fn with_driver(mut self, driver: task::Driver, runtime: Arc<dyn ContainerRuntime>) -> Self {
+        self.runtimes.insert(driver, runtime);
+        self
+    }
+
+    /// Keeps the tasks in `store` instead of only in memory.
+    pub fn with_store(mut self, store: Arc<dyn TaskStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn runtime(&self, driver: task::Driver) -> Result<Arc<dyn ContainerRuntime>, Error> {
+        let runtime = self.runtimes.get(&driver).cloned();
+        runtime.ok_or(Error::DriverUnavailable(driver))
+    }
+
+    /// Takes back the tasks in the store from before the worker restarted.
+    /// The ones that were waiting to be started or stopped are queued again and
+    /// the ones between two containers restarted after their backoff. Running
+    /// ones are left to `adopt_containers` and `inspect_tasks`.
+    pub fn load_tasks(&self) -> std::io::Result<usize> {
+        let tasks = self.store.load()?;
+        let count = tasks.len();
+        for mut t in tasks {
+            if !t.state.is_terminal() {
+                self.hold(&mut t);
+            }
+            match t.state {
+                task::State::Scheduled | task::State::Stopping => self.add_task(t.clone()),
+                task::State::Restarting => {
+                    let at = Utc::now() + self.restart_delay(t.restart_count);
+                    self.restarts.lock().unwrap().push((at, t.clone()));
+                }
+                _ => (),
+            }
+            self.db.lock().unwrap().insert(t.id, t);
+        }
+        Ok(count)
+    }
+
+    /// What the host has for tasks, with the overcommit.
+    pub fn allocatable(&self) -> Resources {
+        Resources::allocatable(&self.stats.load(), self.overcommit, &self.data_dir)
+    }
+
+    /// Takes back the resources and ports of a task that already runs, or is
+    /// about to again, whether they fit or not.
+    fn hold(&self, t: &mut Task) {
+        self.capacity.lock().unwrap().hold(t);
+        if let Err(e) = self.ports.lock().unwrap().allocate(t) {
+            error!("[WORKER] Can't reserve the ports of task {:?}: {}", t.id, e);
+        }
+    }
+
+    /// Gives back what the task held, once it is done or won't start.
+    fn release(&self, task_id: Uuid) {
+        self.capacity.lock().unwrap().release(task_id);
+        self.ports.lock().unwrap().release(task_id);
+    }
+
+    /// Records a change to a task, in `db` and in the store.
+    fn save(&self, t: Task) {
+        let mut db = self.db.lock().unwrap();
+        self.persist(&t);
+        db.insert(t.id, t);
+    }
+
+    /// Records how a start ended, unless the task was cancelled or asked to
+    /// stop meanwhile. Returns whether it was recorded.
+    fn save_unless_stopped(&self, t: &Task) -> bool {
+        let mut db = self.db.lock().unwrap();
+        let stopped = db.get(&t.id).is_some_and(|stored| {
+            matches!(stored.state, task::State::Cancelled | task::State::Stopping)
+        });
+        if !stopped {
+            self.persist(t);
+            db.insert(t.id, t.clone());
+        }
+        !stopped
+    }
+
+    /// Puts a task changed in place in `db` in the store. Errors are only
+    /// logged, the worker goes on with what it has in memory.
+    fn persist(&self, t: &Task) {
+        if let Err(e) = self.store.put(t) {
+            error!("[WORKER] Error storing task {:?}: {}", t.id, e);
         }
     }
 
     pub fn add_task(&self, t: Task) {
         // TODO: think of a way to deal with lock errors like this.
         self.queue.lock().unwrap().push_back(t);
+        self.queued.notify_one();
     }
 
-    pub async fn run_task(&self) -> task::DockerResult {
-        let t = self.queue.lock().unwrap().pop_front();
-        if t.is_none() {
-            info!("[WORKER] No tasks in queue");
-            return task::DockerResult::success("", "", "");
+    /// Entry point for tasks coming in through the api. Tasks that are about to
+    /// be started get their resources and host ports reserved here, so a task
+    /// that doesn't fit or a conflict is reported to the caller instead of
+    /// failing later in docker.
+    pub fn submit_task(&self, mut t: Task) -> Result<Task, Error> {
+        self.runtime(t.driver)?;
+        let mut probes = t.liveness.iter().chain(&t.readiness);
+        if probes.any(|p| !health::supports(t.driver, &p.action)) {
+            return Err(Error::UnsupportedProbe(t.driver));
+        }
+        if t.state == task::State::Scheduled {
+            let allocatable = self.allocatable();
+            self.capacity
+                .lock()
+                .unwrap()
+                .reserve(&t, allocatable)
+                .map_err(Error::Capacity)?;
+            if let Err(e) = self.ports.lock().unwrap().allocate(&mut t) {
+                self.capacity.lock().unwrap().release(t.id);
+                return Err(Error::Ports(e));
+            }
+        }
+        // known from now on, so it can be stopped before it starts
+        self.db.lock().unwrap().entry(t.id).or_insert_with(|| {
+            self.persist(&t);
+            t.clone()
+        });
+        self.add_task(t.clone());
+        Ok(t)
+    }
+
+    /// Asks for a task to be stopped. Tasks that did not start yet are
+    /// cancelled right away, the others move to `Stopping` and are stopped by
+    /// `run_task`.
+    pub fn request_stop(&self, task_id: Uuid) -> Result<Task, Error> {
+        let mut db = self.db.lock().unwrap();
+        let t = db.get_mut(&task_id).ok_or(Error::TaskNotFound(task_id))?;
+        match t.state {
+            task::State::Stopping => return Ok(t.clone()),
+            task::State::Pending | task::State::Scheduled => {
+                t.transition(
+                    task::State::Cancelled,
+                    "stopped before it started",
+                    Actor::Api,
+                )?;
+                self.release(t.id);
+                self.persist(t);
+                return Ok(t.clone());
+            }
+            _ => t.transition(task::State::Stopping, "stop requested", Actor::Api)?,
         }
+        self.persist(t);
+        // a restart waiting out its backoff won't happen anymore
+        self.restarts
+            .lock()
+            .unwrap()
+            .retain(|(_, r)| r.id != task_id);
+        self.add_task(t.clone());
+        Ok(t.clone())
+    }
+
+    /// Runs the next task in the queue. Returns the container the task was
+    /// started or stopped in, or `None` if the queue was empty.
+    pub async fn run_task(&self) -> Result<Option<ContainerHandle>, Error> {
+        let Some(t) = self.next_task() else {
+            info!("[WORKER] No tasks in queue");
+            return Ok(None);
+        };
+        let id = t.id;
+        let result = self.execute(t).await;
+        self.finish(id);
+        result
+    }
+
+    /// Takes the first task in the queue that isn't busy and marks it busy
+    /// until `finish`.
+    fn next_task(&self) -> Option<Task> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut busy = self.busy.lock().unwrap();
+        let i = queue.iter().position(|t| !busy.contains(&t.id))?;
+        let t = queue.remove(i)?;
+        busy.insert(t.id);
+        Some(t)
+    }
 
-        let t = t.unwrap();
+    /// Lets what is queued next for the task run.
+    fn finish(&self, task_id: Uuid) {
+        self.busy.lock().unwrap().remove(&task_id);
+        self.queued.notify_one();
+    }
 
+    async fn execute(&self, t: Task) -> Result<Option<ContainerHandle>, Error> {
         let persisted_t = {
             let mut db = self.db.lock().unwrap();
-            db.entry(t.id).or_insert(t.clone()).clone()
+            db.entry(t.id)
+                .or_insert_with(|| {
+                    self.persist(&t);
+                    t.clone()
+                })
+                .clone()
         };
 
+        if persisted_t.state == task::State::Cancelled {
+            info!("[WORKER] Task {:?} was cancelled, dropping it", t.id);
+            return Ok(None);
+        }
+
         if !task::is_valid_transition(persisted_t.state, t.state) {
-            let err = format!(
-                "[WORKER] Invalid state transition from {:?} to {:?}\n",
+            error!(
+                "[WORKER] Invalid state transition from {:?} to {:?}",
                 persisted_t.state, t.state
             );
-            error!("{}", err);
-            return task::DockerResult::error(&err);
+            return Err(Error::InvalidTransition(persisted_t.state, t.state));
         }
 
         match t.state {
-            task::State::Scheduled => self.start_task(t).await,
-            task::State::Completed => self.stop_task(t).await,
+            task::State::Scheduled | task::State::Restarting => self.start_task(t).await.map(Some),
+            task::State::Stopping => self.stop_task(t).await.map(Some),
             _ => {
                 error!("[WORKER] Invalid state transition to {:?}", t.state);
-                task::DockerResult::error("Invalid state transition")
+                Err(Error::InvalidTransition(persisted_t.state, t.state))
             }
         }
     }
-    pub async fn start_task(&self, mut t: Task) -> task::DockerResult {
+
+    pub async fn start_task(&self, mut t: Task) -> Result<ContainerHandle, Error> {
+        let runtime = self.runtime(t.driver)?;
         t.start_time = Utc::now();
-        let config = task::new_config(&t);
-        let d = task::new_docker(config);
-
-        let result = d.run().await;
-        if result.error.is_some() {
-            let error = result.error.as_ref().unwrap();
-            error!("[WORKER] Error running task {:?}: {}", t.id, error);
-            t.state = task::State::Failed;
-            self.db.lock().unwrap().insert(t.id, t);
-            return result;
+        t.attempts += 1;
+
+        let handle = match self.pull_and_run(runtime.as_ref(), &t).await {
+            Ok(handle) => handle,
+            Err(e) if e.is_retryable() => {
+                error!("[WORKER] Could not start task {:?}, retrying: {}", t.id, e);
+                self.add_task(t);
+                return Err(Error::Runtime(e));
+            }
+            Err(e) => {
+                error!("[WORKER] Error running task {:?}: {}", t.id, e);
+                t.transition(task::State::Failed, &e.to_string(), Actor::Worker)?;
+                t.failure_reason = Some((&e).into());
+                // a task stopped meanwhile is left to whoever stopped it
+                if self.save_unless_stopped(&t) {
+                    self.release(t.id);
+                } else {
+                    info!("[WORKER] Task {:?} was stopped while starting", t.id);
+                }
+                return Err(Error::Runtime(e));
+            }
+        };
+
+        match runtime.inspect(&handle.id).await {
+            Ok(info) => t.host_ports = info.ports,
+            Err(e) => error!("[WORKER] Could not read ports of task {:?}: {}", t.id, e),
         }
 
-        t.container_id = result.container_id.clone();
-        t.state = task::State::Running;
-        self.db.lock().unwrap().insert(t.id, t);
-        return result;
+        t.container_id = handle.id.clone();
+        t.transition(task::State::Running, "container started", Actor::Worker)?;
+        // a stop that came in while the container was starting wins over it
+        if !self.save_unless_stopped(&t) {
+            info!(
+                "[WORKER] Task {:?} was stopped while starting, removing its container",
+                t.id
+            );
+            match runtime.stop(&handle.id, &t.stop_options()).await {
+                Ok(_) | Err(RuntimeError::NotFound(_)) => self.remove_container(&t).await,
+                Err(e) => error!("[WORKER] Error stopping task {:?}: {}", t.id, e),
+            }
+        }
+        Ok(handle)
     }
-    pub async fn stop_task(&self, mut t: Task) -> task::DockerResult {
-        let config = task::new_config(&t);
-        let d = task::new_docker(config);
 
-        let result = d.stop(&t.container_id).await;
-        if result.error.is_some() {
-            error!(
-                "[WORKER] Error stopping task: {:?}",
-                result.error.as_ref().unwrap()
-            );
+    async fn pull_and_run(
+        &self,
+        runtime: &dyn ContainerRuntime,
+        t: &Task,
+    ) -> Result<ContainerHandle, RuntimeError> {
+        let mut config = task::new_config(t);
+        config.registry_auth = self.registry_auth_for(t)?;
+        self.pull_image(runtime, t.id, &config).await?;
+        runtime.run(&config).await
+    }
+
+    /// Finds the credentials for the registry of the task's image. Tasks that
+    /// name a credential fail if the worker has no such credential for that
+    /// registry, the others use whatever the worker has for it.
+    fn registry_auth_for(&self, t: &Task) -> Result<Option<RegistryAuth>, RuntimeError> {
+        let host = task::registry_host(&t.image);
+        let auth = self.registry_auth.get(host);
+        match (t.registry_credential.as_ref(), auth) {
+            (Some(name), Some(auth)) if auth.name == *name => Ok(Some(auth.clone())),
+            (Some(name), _) => Err(RuntimeError::ImagePull(format!(
+                "no registry credential {:?} for {}",
+                name, host
+            ))),
+            (None, auth) => Ok(auth.cloned()),
+        }
+    }
+
+    /// Pulls the task's image, keeping track of the progress in `self.pulls`
+    /// while it runs.
+    async fn pull_image(
+        &self,
+        runtime: &dyn ContainerRuntime,
+        id: Uuid,
+        config: &task::Config,
+    ) -> Result<(), RuntimeError> {
+        let report = |p: &PullProgress| {
+            self.pulls.lock().unwrap().insert(id, p.clone());
+        };
+        let result = runtime.pull(config, &report).await;
+        self.pulls.lock().unwrap().remove(&id);
+        result
+    }
+
+    pub async fn inspect_tasks(&self) {
+        let running = {
+            let db = self.db.lock().unwrap();
+            let busy = self.busy.lock().unwrap();
+            // the ones being stopped are handled by whoever stops them
+            db.values()
+                .filter(|t| t.state == task::State::Running && !busy.contains(&t.id))
+                .cloned()
+                .collect::<Vec<Task>>()
+        };
+
+        for t in running {
+            let runtime = match self.runtime(t.driver) {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("[WORKER] Can't inspect task {:?}: {}", t.id, e);
+                    continue;
+                }
+            };
+            let (exit_code, failure_reason, finish_time, reason) =
+                match runtime.inspect(&t.container_id).await {
+                    Ok(info) if info.running => continue,
+                    Ok(info) => {
+                        info!(
+                            "[WORKER] Container {:?} for task {:?} exited with {:?}",
+                            t.container_id, t.id, info.exit_code
+                        );
+                        let failure_reason = match info.exit_code {
+                            _ if info.oom_killed => Some(FailureReason::OomKilled),
+                            Some(0) => None,
+                            _ => Some(FailureReason::NonZeroExit),
+                        };
+                        let finish_time = info.finished_at.unwrap_or_else(Utc::now);
+                        let reason = match info.exit_code {
+                            Some(code) => format!("container exited with {}", code),
+                            None => "container exited".to_string(),
+                        };
+                        self.remove_container(&t).await;
+                        (info.exit_code, failure_reason, finish_time, reason)
+                    }
+                    Err(e @ RuntimeError::NotFound(_)) => {
+                        error!("[WORKER] Container for task {:?} is gone: {}", t.id, e);
+                        let reason = "container is gone".to_string();
+                        (None, Some((&e).into()), Utc::now(), reason)
+                    }
+                    Err(e) => {
+                        error!("[WORKER] Error inspecting task {:?}: {}", t.id, e);
+                        continue;
+                    }
+                };
+
+            // the task may have been stopped while we were inspecting it
+            let mut db = self.db.lock().unwrap();
+            if let Some(t) = db.get_mut(&t.id) {
+                if t.state == task::State::Running {
+                    t.exit_code = exit_code;
+                    t.failure_reason = failure_reason;
+                    t.finish_time = Some(finish_time);
+                    self.container_stopped(t, &reason);
+                    self.persist(t);
+                }
+            }
+        }
+    }
+
+    /// Samples the usage of every running task's container into `self.usage`,
+    /// dropping the samples of tasks that stopped running.
+    pub async fn sample_usage(&self) {
+        let running = {
+            let db = self.db.lock().unwrap();
+            db.values()
+                .filter(|t| t.state == task::State::Running)
+                .cloned()
+                .collect::<Vec<Task>>()
+        };
+
+        let samples = running.iter().map(|t| async move {
+            let runtime = self.runtime(t.driver)?;
+            let stats = runtime.stats(&t.container_id).await;
+            stats.map(|s| (t.id, s)).map_err(Error::Runtime)
+        });
+        let samples = futures::future::join_all(samples).await;
+
+        let mut usage = self.usage.lock().unwrap();
+        usage.retain(|id, _| running.iter().any(|t| t.id == *id));
+        for sample in samples {
+            match sample {
+                Ok((id, stats)) => {
+                    usage.insert(id, stats);
+                }
+                Err(e) => error!("[WORKER] Error sampling task usage: {}", e),
+            }
+        }
+    }
+
+    /// The latest usage sample of a running task, taken now if there is none
+    /// yet.
+    pub async fn task_stats(&self, task_id: Uuid) -> Result<ContainerStats, Error> {
+        let t = self.db.lock().unwrap().get(&task_id).cloned();
+        let t = t.ok_or(Error::TaskNotFound(task_id))?;
+        if t.state != task::State::Running {
+            return Err(Error::NotRunning(task_id));
+        }
+        if let Some(stats) = self.usage.lock().unwrap().get(&task_id) {
+            return Ok(stats.clone());
+        }
+        let runtime = self.runtime(t.driver)?;
+        let stats = runtime.stats(&t.container_id).await;
+        let stats = stats.map_err(Error::Runtime)?;
+        self.usage.lock().unwrap().insert(task_id, stats.clone());
+        Ok(stats)
+    }
+
+    /// Runs the liveness and readiness probes that are due and records the
+    /// results in `t.health`. A task whose liveness probe reached its failure
+    /// threshold is stopped and then restarted or failed, like a container that
+    /// exited with an error.
+    pub async fn check_health(&self) {
+        let running = {
+            let db = self.db.lock().unwrap();
+            db.values()
+                .filter(|t| t.state == task::State::Running)
+                .cloned()
+                .collect::<Vec<Task>>()
+        };
+
+        let checks = running.iter().filter_map(|t| {
+            let runtime = self.runtime(t.driver).ok()?;
+            Some(async move {
+                let runtime = runtime.as_ref();
+                let liveness = health::run_if_due(runtime, t, &t.liveness, &t.health.liveness);
+                let readiness = health::run_if_due(runtime, t, &t.readiness, &t.health.readiness);
+                let (liveness, readiness) = futures::join!(liveness, readiness);
+                (t.id, liveness, readiness)
+            })
+        });
+        let results = futures::future::join_all(checks).await;
+
+        let mut unhealthy = vec![];
+        {
+            let mut db = self.db.lock().unwrap();
+            for (id, liveness, readiness) in results {
+                // no probe was due, nothing changed
+                if liveness.is_none() && readiness.is_none() {
+                    continue;
+                }
+                // the task may have stopped while it was being probed
+                let Some(t) = db.get_mut(&id).filter(|t| t.state == task::State::Running) else {
+                    continue;
+                };
+                if let (Some(result), Some(probe)) = (readiness, t.readiness.as_ref()) {
+                    t.health.readiness.record(probe, result);
+                }
+                if let (Some(result), Some(probe)) = (liveness, t.liveness.as_ref()) {
+                    t.health.liveness.record(probe, result);
+                }
+
+                // one that is being stopped already is left to that
+                if t.health.liveness.healthy == Some(false) && self.busy.lock().unwrap().insert(id)
+                {
+                    let err = t.health.liveness.last_error.clone().unwrap_or_default();
+                    error!(
+                        "[WORKER] Task {:?} failed its liveness probe: {}",
+                        t.id, err
+                    );
+                    unhealthy.push(t.clone());
+                }
+                self.persist(t);
+            }
+        }
+
+        // the container is gone before the task is restarted, or its
+        // resources released, so the next one can have its name and ports
+        for t in unhealthy {
+            let stopped = match self.runtime(t.driver) {
+                Ok(runtime) => runtime
+                    .stop(&t.container_id, &t.stop_options())
+                    .await
+                    .map_err(Error::Runtime),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = stopped {
+                error!("[WORKER] Error stopping unhealthy task {:?}: {}", t.id, e);
+            }
+            self.remove_container(&t).await;
+
+            {
+                let mut db = self.db.lock().unwrap();
+                // a stop requested in the meantime finishes the task instead
+                if let Some(t) = db
+                    .get_mut(&t.id)
+                    .filter(|t| t.state == task::State::Running)
+                {
+                    let err = t.health.liveness.last_error.clone().unwrap_or_default();
+                    t.failure_reason = Some(FailureReason::Unhealthy(err));
+                    t.finish_time = Some(Utc::now());
+                    self.container_stopped(t, "liveness probe failed");
+                    self.persist(t);
+                }
+            }
+            self.finish(t.id);
+        }
+    }
+
+    /// Decides what happens to a running task whose container stopped without
+    /// being asked to: it runs again if its restart policy says so, after a
+    /// backoff, otherwise it ends up `Completed`, or `Failed` if it has a
+    /// `failure_reason`.
+    fn container_stopped(&self, t: &mut Task, reason: &str) {
+        let failed = t.failure_reason.is_some();
+        if !t.restart_policy.should_restart(failed, t.restart_count) {
+            let state = if failed {
+                task::State::Failed
+            } else {
+                task::State::Completed
+            };
+            if let Err(e) = t.transition(state, reason, Actor::Worker) {
+                error!("[WORKER] Task {:?}: {}", t.id, e);
+            }
+            self.release(t.id);
+            return;
+        }
+
+        if let Err(e) = t.transition(task::State::Restarting, reason, Actor::Worker) {
+            error!("[WORKER] Task {:?}: {}", t.id, e);
+            return;
+        }
+        // the host ports stay reserved for the next container
+        t.restart_count += 1;
+        t.container_id = String::new();
+        t.health = task::Health::default();
+        let delay = self.restart_delay(t.restart_count);
+        info!(
+            "[WORKER] Restarting task {:?} in {:?}, restart {}",
+            t.id, delay, t.restart_count
+        );
+        let at = Utc::now() + delay;
+        self.restarts.lock().unwrap().push((at, t.clone()));
+        // it may be due before `run_tasks_loop` would look again
+        self.queued.notify_one();
+    }
+
+    fn restart_delay(&self, restart_count: u32) -> Duration {
+        let factor = 2u32.saturating_pow(restart_count.saturating_sub(1));
+        let delay = self.restart_backoff.saturating_mul(factor);
+        delay.min(self.restart_backoff_max)
+    }
+
+    /// How long until the next restart is due, if there is one.
+    fn next_restart_in(&self) -> Option<Duration> {
+        let restarts = self.restarts.lock().unwrap();
+        let at = restarts.iter().map(|(at, _)| *at).min()?;
+        Some((at - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+    }
+
+    /// Moves the restarts whose backoff is over to the queue.
+    pub fn queue_due_restarts(&self) {
+        let now = Utc::now();
+        let due = {
+            let mut restarts = self.restarts.lock().unwrap();
+            let (due, waiting) = restarts.drain(..).partition(|(at, _)| *at <= now);
+            *restarts = waiting;
+            due
+        };
+        for (_, t) in due {
+            self.add_task(t);
+        }
+    }
+
+    /// Finds the containers started before the worker restarted, through the
+    /// labels `task::new_config` puts on them, and takes their tasks back into
+    /// `db`. Their tasks are `Running` again, and the ones whose container
+    /// exited in the meantime are then handled like any other that exits.
+    /// Containers without a usable task, or of a task that already has another
+    /// container, are orphans: logged, and removed if `remove_orphans` is set.
+    pub async fn adopt_containers(&self) -> Adoption {
+        let mut adoption = Adoption::default();
+        for (driver, runtime) in &self.runtimes {
+            let containers = match runtime.list(task::TASK_ID_LABEL).await {
+                Ok(containers) => containers,
+                Err(e) => {
+                    error!(
+                        "[WORKER] Can't list containers of the {:?} driver: {}",
+                        driver, e
+                    );
+                    continue;
+                }
+            };
+            for c in containers {
+                match self.adopt_container(runtime.as_ref(), &c).await {
+                    Ok(task_id) => {
+                        info!(
+                            "[WORKER] Adopted container {:?} of task {:?}",
+                            c.id, task_id
+                        );
+                        adoption.adopted.push(task_id);
+                    }
+                    Err(reason) => {
+                        error!(
+                            "[WORKER] Orphaned container {:?} ({}): {}",
+                            c.id, c.name, reason
+                        );
+                        if self.remove_orphans {
+                            remove_orphan(runtime.as_ref(), &c).await;
+                        }
+                        adoption.orphans.push(c.id);
+                    }
+                }
+            }
+        }
+        self.inspect_tasks().await;
+        adoption
+    }
+
+    /// Rebuilds the task of a labeled container. Returns why it can't be. A
+    /// task the store kept is taken as it is there, the label has no `env` and
+    /// nothing of what happened to the task.
+    async fn adopt_container(
+        &self,
+        runtime: &dyn ContainerRuntime,
+        c: &ContainerSummary,
+    ) -> Result<Uuid, String> {
+        let spec = c
+            .labels
+            .get(task::TASK_SPEC_LABEL)
+            .ok_or("it has no task spec")?;
+        let mut t: Task =
+            serde_json::from_str(spec).map_err(|e| format!("invalid task spec: {}", e))?;
+        match self.db.lock().unwrap().get(&t.id) {
+            Some(known) if known.container_id == c.id => return Ok(t.id),
+            Some(known) => {
+                let e = format!("task {} runs in {:?}", t.id, known.container_id);
+                return Err(e);
+            }
+            None => (),
         }
+
+        let info = runtime.inspect(&c.id).await.map_err(|e| e.to_string())?;
+        t.container_id = c.id.clone();
+        t.host_ports = info.ports;
+        t.transition(
+            task::State::Running,
+            "container adopted after the worker restarted",
+            Actor::Worker,
+        )
+        .map_err(|e| e.to_string())?;
+        self.hold(&mut t);
+        let task_id = t.id;
+        self.save(t);
+        Ok(task_id)
+    }
+
+    /// Copies the logs of the task's stopped container to `self.logs` and
+    /// removes it. Errors are only logged, the task is done either way.
+    async fn remove_container(&self, t: &Task) {
+        let runtime = match self.runtime(t.driver) {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                error!("[WORKER] Can't remove container of task {:?}: {}", t.id, e);
+                return;
+            }
+        };
+        let logs = match runtime.logs(&t.container_id, &LogOptions::default()).await {
+            Ok(logs) => logs.try_collect::<Vec<_>>().await,
+            Err(e) => Err(e),
+        };
+        match logs {
+            Ok(logs) => {
+                if let Err(e) = self.logs.append(t.id, &logs) {
+                    error!("[WORKER] Error storing logs of task {:?}: {}", t.id, e);
+                }
+            }
+            // already removed, its logs are gone with it
+            Err(RuntimeError::NotFound(_)) => return,
+            Err(e) => error!("[WORKER] Error reading logs of task {:?}: {}", t.id, e),
+        }
+
+        match runtime.remove(&t.container_id).await {
+            Ok(()) | Err(RuntimeError::NotFound(_)) => (),
+            Err(e) => error!(
+                "[WORKER] Error removing container of task {:?}: {}",
+                t.id, e
+            ),
+        }
+    }
+
+    /// Stops a task in `Stopping`. One that is between containers, waiting to
+    /// be restarted, only needs its state changed.
+    pub async fn stop_task(&self, mut t: Task) -> Result<ContainerHandle, Error> {
+        if t.container_id.is_empty() {
+            t.transition(
+                task::State::Completed,
+                "stopped while restarting",
+                Actor::Worker,
+            )?;
+            t.finish_time = Some(Utc::now());
+            self.release(t.id);
+            self.save(t);
+            return Ok(ContainerHandle::default());
+        }
+
+        let runtime = self.runtime(t.driver)?;
+        let options = t.stop_options();
+        let outcome = match runtime.stop(&t.container_id, &options).await {
+            Ok(outcome) => Some(outcome),
+            // the container is already gone, which is what we wanted anyway
+            Err(RuntimeError::NotFound(e)) => {
+                info!("[WORKER] Container for task {:?} not found: {}", t.id, e);
+                None
+            }
+            Err(e) if e.is_retryable() => {
+                error!("[WORKER] Could not stop task {:?}, retrying: {}", t.id, e);
+                self.add_task(t);
+                return Err(Error::Runtime(e));
+            }
+            Err(e) => {
+                error!("[WORKER] Error stopping task {:?}: {}", t.id, e);
+                t.transition(task::State::Failed, &e.to_string(), Actor::Worker)?;
+                t.failure_reason = Some((&e).into());
+                self.release(t.id);
+                self.save(t);
+                return Err(Error::Runtime(e));
+            }
+        };
+
+        let handle = ContainerHandle {
+            id: t.container_id.clone(),
+        };
+        self.remove_container(&t).await;
         t.finish_time = Some(Utc::now());
-        t.state = task::State::Completed;
-        error!(
+        t.stop_outcome = outcome;
+        let reason = match outcome {
+            Some(StopOutcome::Killed) => format!(
+                "container killed, still running {}s after {}",
+                t.stop_grace_secs, t.stop_signal
+            ),
+            _ => "container stopped".to_string(),
+        };
+        t.transition(task::State::Completed, &reason, Actor::Worker)?;
+        self.release(t.id);
+        info!(
             "[WORKER] Stopped and removed container {:?} for task {:?}",
             t.container_id, t.id
         );
-        self.db.lock().unwrap().insert(t.id, t);
-        return result;
+        self.save(t);
+        Ok(handle)
+    }
+}
+
+async fn remove_orphan(runtime: &dyn ContainerRuntime, c: &ContainerSummary) {
+    let stopped = match runtime.stop(&c.id, &task::StopOptions::default()).await {
+        Ok(_) => runtime.remove(&c.id).await,
+        Err(e) => Err(e),
+    };
+    match stopped {
+        Ok(()) => info!("[WORKER] Removed orphaned container {:?}", c.id),
+        Err(e) => error!(
+            "[WORKER] Error removing orphaned container {:?}: {}",
+            c.id, e
+        ),
     }
 }
 
@@ -137,3 +1048,989 @@ pub async fn collect_stats(worker: Arc<Worker>) -> () {
         tokio::time::sleep(Duration::from_secs(10)).await;
     }
 }
+
+/// The default config with a log dir of its own, so tests running at the same
+/// time don't see each other's logs.
+#[cfg(test)]
+pub(crate) fn test_config() -> Config {
+    let log_dir = std::env::temp_dir().join(format!("cube-test-logs-{}", Uuid::new_v4()));
+    Config {
+        log_dir,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{FakeRuntime, LogOptions, LogSource};
+    use futures::StreamExt;
+
+    fn fake_worker() -> (Worker, Arc<FakeRuntime>) {
+        fake_worker_with(test_config())
+    }
+
+    fn fake_worker_with(config: Config) -> (Worker, Arc<FakeRuntime>) {
+        let runtime = Arc::new(FakeRuntime::new());
+        let worker = Worker::with_config("test-worker", config, runtime.clone());
+        (worker, runtime)
+    }
+
+    fn scheduled_task() -> Task {
+        Task {
+            name: "test-task".to_string(),
+            state: task::State::Scheduled,
+            image: "strm/helloworld-http".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_and_stop_task() {
+        let (w, runtime) = fake_worker();
+        let t = scheduled_task();
+
+        w.add_task(t.clone());
+        let handle = w.run_task().await.unwrap().unwrap();
+        assert_eq!(handle.id, "fake-1");
+
+        let running = w.db.lock().unwrap().get(&t.id).cloned().unwrap();
+        assert_eq!(running.state, task::State::Running);
+        assert_eq!(running.container_id, "fake-1");
+        assert!(runtime.container("fake-1").unwrap().running);
+
+        w.request_stop(t.id).unwrap();
+        let stopping = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(stopping.state, task::State::Stopping);
+        w.run_task().await.unwrap();
+
+        let stopped = w.db.lock().unwrap().get(&t.id).cloned().unwrap();
+        assert_eq!(stopped.state, task::State::Completed);
+        assert!(stopped.finish_time.is_some());
+        let steps: Vec<_> = stopped.history.iter().map(|t| (t.to, t.actor)).collect();
+        assert_eq!(
+            steps,
+            [
+                (task::State::Running, Actor::Worker),
+                (task::State::Stopping, Actor::Api),
+                (task::State::Completed, Actor::Worker),
+            ]
+        );
+        assert_eq!(runtime.container_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_stop() {
+        let (w, runtime) = fake_worker();
+        let service = Task {
+            name: "service".to_string(),
+            stop_signal: "SIGTERM".to_string(),
+            stop_grace_secs: 60,
+            ..scheduled_task()
+        };
+        let stuck = Task {
+            name: "stuck".to_string(),
+            ..scheduled_task()
+        };
+        w.add_task(service.clone());
+        w.add_task(stuck.clone());
+        let service_container = w.run_task().await.unwrap().unwrap();
+        let stuck_container = w.run_task().await.unwrap().unwrap();
+        runtime.ignore_stop_signal(&stuck_container.id);
+
+        w.request_stop(service.id).unwrap();
+        w.request_stop(stuck.id).unwrap();
+        w.run_task().await.unwrap();
+        w.run_task().await.unwrap();
+
+        let stops = runtime.stops();
+        assert_eq!(stops[0].0, service_container.id);
+        assert_eq!(stops[0].1.signal, "SIGTERM");
+        assert_eq!(stops[0].1.grace, Duration::from_secs(60));
+
+        let db = w.db.lock().unwrap();
+        assert_eq!(db[&service.id].state, task::State::Completed);
+        assert_eq!(db[&service.id].stop_outcome, Some(StopOutcome::Exited));
+        assert_eq!(db[&stuck.id].state, task::State::Completed);
+        assert_eq!(db[&stuck.id].stop_outcome, Some(StopOutcome::Killed));
+        assert_eq!(
+            db[&stuck.id].state_reason,
+            "container killed, still running 10s after SIGTERM"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drivers() {
+        let (w, docker) = fake_worker();
+        let process = Task {
+            driver: task::Driver::Process,
+            ..scheduled_task()
+        };
+        let err = w.submit_task(process.clone()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DriverUnavailable(task::Driver::Process)
+        ));
+
+        let processes = Arc::new(FakeRuntime::new());
+        let w = w.with_driver(task::Driver::Process, processes.clone());
+        w.submit_task(process.clone()).unwrap();
+        w.run_task().await.unwrap();
+        assert_eq!(processes.container_count(), 1);
+        assert_eq!(docker.container_count(), 0);
+
+        w.request_stop(process.id).unwrap();
+        w.run_task().await.unwrap();
+        assert_eq!(processes.container_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_process_probes() {
+        let (w, _) = fake_worker();
+        let w = w.with_driver(task::Driver::Process, Arc::new(FakeRuntime::new()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = |action| task::Probe {
+            action,
+            interval_secs: 0,
+            timeout_secs: 1,
+            failure_threshold: 1,
+        };
+        let exec = Task {
+            driver: task::Driver::Process,
+            liveness: Some(probe(task::ProbeAction::Exec {
+                cmd: "true".to_string(),
+            })),
+            ..scheduled_task()
+        };
+        let err = w.submit_task(exec).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnsupportedProbe(task::Driver::Process)
+        ));
+
+        // no port is published, the process listens on the host
+        let t = Task {
+            driver: task::Driver::Process,
+            liveness: Some(probe(task::ProbeAction::Tcp {
+                port: format!("{}/tcp", port).parse().unwrap(),
+            })),
+            ..scheduled_task()
+        };
+        w.submit_task(t.clone()).unwrap();
+        w.run_task().await.unwrap();
+        w.check_health().await;
+        let checked = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(checked.state, task::State::Running);
+        assert_eq!(checked.health.liveness.healthy, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_scheduled_task() {
+        let (w, runtime) = fake_worker();
+        let t = scheduled_task();
+        w.submit_task(t.clone()).unwrap();
+
+        let cancelled = w.request_stop(t.id).unwrap();
+        assert_eq!(cancelled.state, task::State::Cancelled);
+        assert!(w.run_task().await.unwrap().is_none());
+        assert_eq!(runtime.container_count(), 0);
+
+        let err = w.request_stop(t.id).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidTransition(task::State::Cancelled, task::State::Stopping)
+        ));
+        let err = w.request_stop(Uuid::new_v4()).unwrap_err();
+        assert!(matches!(err, Error::TaskNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_task_logs() {
+        let (w, runtime) = fake_worker();
+        w.add_task(scheduled_task());
+        let handle = w.run_task().await.unwrap().unwrap();
+        runtime.push_log(&handle.id, LogSource::Stdout, "listening on :80");
+        runtime.push_log(&handle.id, LogSource::Stderr, "warning: no config");
+        runtime.push_log(&handle.id, LogSource::Stdout, "GET /");
+
+        let read = |options: LogOptions| {
+            let runtime = w.runtime(task::Driver::Docker).unwrap();
+            let id = handle.id.clone();
+            async move {
+                let logs = runtime.logs(&id, &options).await.unwrap();
+                let chunks: Vec<_> = logs.map(|c| c.unwrap().message).collect().await;
+                chunks.concat()
+            }
+        };
+
+        let all = read(LogOptions::default()).await;
+        assert_eq!(all, "listening on :80\nwarning: no config\nGET /\n");
+        let stdout = LogOptions {
+            stderr: false,
+            ..Default::default()
+        };
+        assert_eq!(read(stdout).await, "listening on :80\nGET /\n");
+        let last = LogOptions {
+            tail: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(read(last).await, "GET /\n");
+    }
+
+    #[tokio::test]
+    async fn test_start_task_records_host_ports() {
+        let (w, _runtime) = fake_worker();
+        let fixed = task::PortBinding {
+            host_ip: "127.0.0.1".to_string(),
+            host_port: 8080,
+        };
+        let t = Task {
+            port_bindings: HashMap::from([
+                ("80/tcp".to_string(), fixed.clone()),
+                ("443/tcp".to_string(), task::PortBinding::default()),
+            ]),
+            ..scheduled_task()
+        };
+
+        w.add_task(t.clone());
+        w.run_task().await.unwrap();
+
+        let running = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(running.host_ports["80/tcp"], fixed);
+        assert_ne!(running.host_ports["443/tcp"].host_port, 0);
+    }
+
+    #[tokio::test]
+    async fn test_submit_task_rejects_port_conflicts() {
+        let (w, _runtime) = fake_worker();
+        let binding = task::PortBinding {
+            host_port: 8080,
+            ..Default::default()
+        };
+        let t = Task {
+            port_bindings: HashMap::from([("80/tcp".to_string(), binding)]),
+            ..scheduled_task()
+        };
+        let other = Task {
+            id: Uuid::new_v4(),
+            name: "other-task".to_string(),
+            ..t.clone()
+        };
+
+        w.submit_task(t.clone()).unwrap();
+        let err = w.submit_task(other.clone()).unwrap_err();
+        assert!(
+            matches!(err, Error::Ports(PortError::Conflict { task_id, .. }) if task_id == t.id)
+        );
+
+        // once the first task is stopped its port is free again
+        w.run_task().await.unwrap();
+        w.request_stop(t.id).unwrap();
+        w.run_task().await.unwrap();
+        w.submit_task(other).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_named_volume_outlives_task() {
+        let (w, runtime) = fake_worker();
+        let t = Task {
+            volumes: vec![task::VolumeMount {
+                kind: task::MountKind::Volume,
+                source: "pgdata".to_string(),
+                target: "/var/lib/postgresql/data".to_string(),
+                read_only: false,
+            }],
+            ..scheduled_task()
+        };
+
+        w.add_task(t.clone());
+        w.run_task().await.unwrap();
+        let err = runtime.remove_volume("pgdata").await.unwrap_err();
+        assert!(matches!(err, RuntimeError::InUse(_)));
+
+        w.request_stop(t.id).unwrap();
+        w.run_task().await.unwrap();
+
+        let volumes = runtime.list_volumes().await.unwrap();
+        assert_eq!(volumes.len(), 1);
+        assert_eq!(volumes[0].name, "pgdata");
+        runtime.remove_volume("pgdata").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_run_marks_task_failed() {
+        let (w, runtime) = fake_worker();
+        let t = scheduled_task();
+
+        runtime.fail_next_run(RuntimeError::ImagePull("image not found".to_string()));
+        w.add_task(t.clone());
+        let result = w.run_task().await;
+        assert!(matches!(
+            result,
+            Err(Error::Runtime(RuntimeError::ImagePull(_)))
+        ));
+
+        let failed = w.db.lock().unwrap().get(&t.id).cloned().unwrap();
+        assert_eq!(failed.state, task::State::Failed);
+        assert_eq!(
+            failed.failure_reason,
+            Some(FailureReason::ImagePull("image not found".to_string()))
+        );
+        assert_eq!(failed.attempts, 1);
+        assert_eq!(runtime.container_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_tasks_detects_exit() {
+        let (w, runtime) = fake_worker();
+        let ok = scheduled_task();
+        let failing = Task {
+            name: "failing-task".to_string(),
+            ..scheduled_task()
+        };
+
+        w.add_task(ok.clone());
+        w.add_task(failing.clone());
+        let ok_container = w.run_task().await.unwrap().unwrap();
+        let failing_container = w.run_task().await.unwrap().unwrap();
+
+        w.inspect_tasks().await;
+        let state = w.db.lock().unwrap()[&ok.id].state;
+        assert_eq!(state, task::State::Running);
+
+        runtime.exit(&ok_container.id, 0);
+        runtime.exit(&failing_container.id, 1);
+        w.inspect_tasks().await;
+
+        let db = w.db.lock().unwrap();
+        assert_eq!(db[&ok.id].state, task::State::Completed);
+        assert!(db[&ok.id].finish_time.is_some());
+        assert_eq!(db[&ok.id].exit_code, Some(0));
+        assert_eq!(db[&failing.id].state, task::State::Failed);
+        assert!(db[&failing.id].finish_time.is_some());
+        assert_eq!(db[&failing.id].exit_code, Some(1));
+        assert_eq!(
+            db[&failing.id].failure_reason,
+            Some(FailureReason::NonZeroExit)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_adopt_containers() {
+        let (w, runtime) = fake_worker();
+        let running = Task {
+            env: HashMap::from([("PGPASSWORD".to_string(), "secret".to_string())]),
+            ..scheduled_task()
+        };
+        let exited = Task {
+            name: "exited-task".to_string(),
+            ..scheduled_task()
+        };
+        w.add_task(running.clone());
+        w.add_task(exited.clone());
+        w.run_task().await.unwrap();
+        let exited_container = w.run_task().await.unwrap().unwrap();
+        runtime.exit(&exited_container.id, 1);
+        let stray = task::Config {
+            name: "stray".to_string(),
+            labels: HashMap::from([(task::TASK_ID_LABEL.to_string(), "?".to_string())]),
+            ..Default::default()
+        };
+        let stray = runtime.run(&stray).await.unwrap();
+
+        // the worker restarts, with nothing but its runtime's containers
+        let config = Config {
+            remove_orphans: true,
+            ..test_config()
+        };
+        let w = Worker::with_config("test-worker", config, runtime.clone());
+        let mut adoption = w.adopt_containers().await;
+        adoption.adopted.sort();
+        let mut adopted = vec![running.id, exited.id];
+        adopted.sort();
+        assert_eq!(adoption.adopted, adopted);
+        assert_eq!(adoption.orphans, vec![stray.id.clone()]);
+        assert!(runtime.container(&stray.id).is_none());
+
+        {
+            let db = w.db.lock().unwrap();
+            let t = &db[&running.id];
+            assert_eq!(t.state, task::State::Running);
+            assert_eq!(t.container_id, "fake-1");
+            assert_eq!(t.image, running.image);
+            // only the store has it
+            assert!(t.env.is_empty());
+            let last = t.history.last().unwrap();
+            assert_eq!(last.reason, "container adopted after the worker restarted");
+            assert_eq!(db[&exited.id].state, task::State::Failed);
+            assert_eq!(db[&exited.id].exit_code, Some(1));
+        }
+
+        // adopting again finds the same containers
+        let again = w.adopt_containers().await;
+        assert_eq!(again.adopted, vec![running.id]);
+        assert!(again.orphans.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tasks_survive_restart() {
+        let store = Arc::new(crate::worker::MemoryStore::new());
+        let (w, runtime) = fake_worker();
+        let w = w.with_store(store.clone());
+        let started = Task {
+            env: HashMap::from([("PGPASSWORD".to_string(), "secret".to_string())]),
+            ..scheduled_task()
+        };
+        let waiting = Task {
+            name: "waiting-task".to_string(),
+            ..scheduled_task()
+        };
+        w.submit_task(started.clone()).unwrap();
+        w.run_task().await.unwrap();
+        w.submit_task(waiting.clone()).unwrap();
+        let history = w.db.lock().unwrap()[&started.id].history.clone();
+
+        // the worker restarts, with its store and its runtime's containers
+        let w = Worker::with_config("test-worker", test_config(), runtime).with_store(store);
+        assert_eq!(w.load_tasks().unwrap(), 2);
+        {
+            let db = w.db.lock().unwrap();
+            assert_eq!(db[&started.id].state, task::State::Running);
+            assert_eq!(db[&started.id].history, history);
+            assert_eq!(db[&waiting.id].state, task::State::Scheduled);
+        }
+        let adoption = w.adopt_containers().await;
+        assert_eq!(adoption.adopted, vec![started.id]);
+        assert!(adoption.orphans.is_empty());
+        {
+            let db = w.db.lock().unwrap();
+            assert_eq!(db[&started.id].history, history);
+            assert_eq!(db[&started.id].env, started.env);
+        }
+
+        // the task that was waiting to start is queued again
+        let handle = w.run_task().await.unwrap().unwrap();
+        let db = w.db.lock().unwrap();
+        assert_eq!(db[&waiting.id].state, task::State::Running);
+        assert_eq!(db[&waiting.id].container_id, handle.id);
+    }
+
+    #[tokio::test]
+    async fn test_next_task_keeps_task_order() {
+        let (w, _) = fake_worker();
+        let first = scheduled_task();
+        let second = Task {
+            name: "second-task".to_string(),
+            ..scheduled_task()
+        };
+        let stop = Task {
+            state: task::State::Stopping,
+            ..first.clone()
+        };
+        w.add_task(first.clone());
+        w.add_task(stop.clone());
+        w.add_task(second.clone());
+
+        let t = w.next_task().unwrap();
+        assert_eq!((t.id, t.state), (first.id, task::State::Scheduled));
+        // the stop waits for the start, the other task doesn't
+        assert_eq!(w.next_task().unwrap().id, second.id);
+        assert!(w.next_task().is_none());
+        w.finish(first.id);
+        let t = w.next_task().unwrap();
+        assert_eq!((t.id, t.state), (first.id, task::State::Stopping));
+    }
+
+    #[tokio::test]
+    async fn test_run_tasks_loop() {
+        let (w, runtime) = fake_worker();
+        let w = Arc::new(w);
+        tokio::spawn(run_tasks_loop(w.clone()));
+
+        let tasks: Vec<_> = (0..6)
+            .map(|i| Task {
+                name: format!("task-{}", i),
+                ..scheduled_task()
+            })
+            .collect();
+        for t in &tasks {
+            w.submit_task(t.clone()).unwrap();
+        }
+        w.request_stop(tasks[0].id).ok();
+
+        // no polling interval to wait out
+        let done = async {
+            loop {
+                let db = w.db.lock().unwrap().clone();
+                let running = tasks[1..]
+                    .iter()
+                    .all(|t| db[&t.id].state == task::State::Running);
+                if running && db[&tasks[0].id].state.is_terminal() {
+                    return db;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let db = tokio::time::timeout(Duration::from_secs(2), done)
+            .await
+            .unwrap();
+        assert_eq!(runtime.container_count(), 5);
+        assert_eq!(db[&tasks[0].id].state, task::State::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_stop_while_starting() {
+        let (w, runtime) = fake_worker_with(Config {
+            restart_backoff: Duration::ZERO,
+            ..test_config()
+        });
+        let w = Arc::new(w);
+        runtime.set_delay(Duration::from_millis(100));
+        let start = |w: Arc<Worker>| tokio::spawn(async move { w.run_task().await });
+
+        let t = scheduled_task();
+        w.submit_task(t.clone()).unwrap();
+        let starting = start(w.clone());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        w.request_stop(t.id).unwrap();
+        starting.await.unwrap().unwrap();
+        assert_eq!(w.db.lock().unwrap()[&t.id].state, task::State::Cancelled);
+        assert_eq!(runtime.container_count(), 0);
+
+        // a restart that is stopped ends up stopped, not running
+        let t = Task {
+            name: "restarting-task".to_string(),
+            restart_policy: task::RestartPolicy::Always,
+            ..scheduled_task()
+        };
+        w.submit_task(t.clone()).unwrap();
+        let first = w.run_task().await.unwrap().unwrap();
+        runtime.exit(&first.id, 1);
+        w.inspect_tasks().await;
+        assert_eq!(w.db.lock().unwrap()[&t.id].state, task::State::Restarting);
+        w.queue_due_restarts();
+        let restarting = start(w.clone());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        w.request_stop(t.id).unwrap();
+        restarting.await.unwrap().unwrap();
+        w.run_task().await.unwrap();
+        assert_eq!(w.db.lock().unwrap()[&t.id].state, task::State::Completed);
+        assert_eq!(runtime.container_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stop_while_pulling() {
+        let (w, runtime) = fake_worker_with(Config {
+            restart_backoff: Duration::ZERO,
+            ..test_config()
+        });
+        let w = Arc::new(w);
+        let start = |w: Arc<Worker>| tokio::spawn(async move { w.run_task().await });
+        let pull_error = || RuntimeError::ImagePull("manifest unknown".to_string());
+
+        let t = scheduled_task();
+        w.submit_task(t.clone()).unwrap();
+        runtime.set_delay(Duration::from_millis(100));
+        runtime.fail_next_pull(pull_error());
+        let starting = start(w.clone());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        w.request_stop(t.id).unwrap();
+        assert!(starting.await.unwrap().is_err());
+        let cancelled = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(cancelled.state, task::State::Cancelled);
+        assert_eq!(cancelled.failure_reason, None);
+
+        // a restart that is stopped completes instead of failing
+        let t = Task {
+            name: "restarting-task".to_string(),
+            restart_policy: task::RestartPolicy::Always,
+            ..scheduled_task()
+        };
+        runtime.set_delay(Duration::ZERO);
+        w.submit_task(t.clone()).unwrap();
+        let first = w.run_task().await.unwrap().unwrap();
+        runtime.exit(&first.id, 1);
+        w.inspect_tasks().await;
+        w.queue_due_restarts();
+        runtime.set_delay(Duration::from_millis(100));
+        runtime.fail_next_pull(pull_error());
+        let restarting = start(w.clone());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        w.request_stop(t.id).unwrap();
+        assert!(restarting.await.unwrap().is_err());
+        w.run_task().await.unwrap();
+        let completed = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(completed.state, task::State::Completed);
+        assert!(completed
+            .history
+            .iter()
+            .all(|t| t.to != task::State::Failed));
+    }
+
+    #[tokio::test]
+    async fn test_sample_usage() {
+        let (w, runtime) = fake_worker();
+        let t = scheduled_task();
+        w.add_task(t.clone());
+        let handle = w.run_task().await.unwrap().unwrap();
+        let stats = task::ContainerStats {
+            cpu_percent: 150.0,
+            memory_bytes: 1024,
+            ..Default::default()
+        };
+        runtime.set_stats(&handle.id, stats);
+
+        // sampled on demand before the first round
+        assert_eq!(w.task_stats(t.id).await.unwrap().memory_bytes, 1024);
+        runtime.set_stats(
+            &handle.id,
+            task::ContainerStats {
+                memory_bytes: 2048,
+                ..Default::default()
+            },
+        );
+        w.sample_usage().await;
+        assert_eq!(w.task_stats(t.id).await.unwrap().memory_bytes, 2048);
+
+        w.request_stop(t.id).unwrap();
+        w.run_task().await.unwrap();
+        w.sample_usage().await;
+        assert!(w.usage.lock().unwrap().is_empty());
+        let err = w.task_stats(t.id).await.unwrap_err();
+        assert!(matches!(err, Error::NotRunning(_)));
+    }
+
+    #[tokio::test]
+    async fn test_logs_kept_after_container_removal() {
+        let (w, runtime) = fake_worker();
+        let batch = scheduled_task();
+        let server = Task {
+            name: "server".to_string(),
+            ..scheduled_task()
+        };
+        w.add_task(batch.clone());
+        w.add_task(server.clone());
+        let batch_container = w.run_task().await.unwrap().unwrap();
+        let server_container = w.run_task().await.unwrap().unwrap();
+        runtime.push_log(&batch_container.id, LogSource::Stderr, "out of cheese");
+        runtime.push_log(&server_container.id, LogSource::Stdout, "listening");
+
+        runtime.exit(&batch_container.id, 1);
+        w.inspect_tasks().await;
+        w.request_stop(server.id).unwrap();
+        w.run_task().await.unwrap();
+
+        assert_eq!(runtime.container_count(), 0);
+        let options = LogOptions::default();
+        let logs = w.logs.read(batch.id, &options).unwrap().unwrap();
+        assert_eq!(logs[0].message, "out of cheese\n");
+        assert_eq!(logs[0].source, LogSource::Stderr);
+        let logs = w.logs.read(server.id, &options).unwrap().unwrap();
+        assert_eq!(logs[0].message, "listening\n");
+    }
+
+    #[tokio::test]
+    async fn test_health_probes() {
+        let (w, runtime) = fake_worker();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let probe = |action| task::Probe {
+            action,
+            interval_secs: 0,
+            timeout_secs: 1,
+            failure_threshold: 2,
+        };
+        let t = Task {
+            port_bindings: HashMap::from([(
+                "80/tcp".to_string(),
+                task::PortBinding {
+                    host_ip: "127.0.0.1".to_string(),
+                    host_port: port,
+                },
+            )]),
+            liveness: Some(probe(task::ProbeAction::Exec {
+                cmd: "pg_isready".to_string(),
+            })),
+            readiness: Some(probe(task::ProbeAction::Tcp {
+                port: "80/tcp".parse().unwrap(),
+            })),
+            ..scheduled_task()
+        };
+        w.add_task(t.clone());
+        let handle = w.run_task().await.unwrap().unwrap();
+
+        w.check_health().await;
+        let health = w.db.lock().unwrap()[&t.id].health.clone();
+        assert_eq!(health.liveness.healthy, Some(true));
+        assert_eq!(health.readiness.healthy, Some(true));
+
+        drop(listener);
+        runtime.set_exec_exit_code(&handle.id, 1);
+        w.check_health().await;
+        let checked = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(checked.state, task::State::Running);
+        assert_eq!(checked.health.liveness.failures, 1);
+        assert_eq!(checked.health.liveness.healthy, Some(true));
+
+        w.check_health().await;
+        let failed = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(failed.state, task::State::Failed);
+        assert_eq!(failed.health.readiness.healthy, Some(false));
+        let reason = "\"pg_isready\" exited with 1".to_string();
+        assert_eq!(
+            failed.failure_reason,
+            Some(FailureReason::Unhealthy(reason))
+        );
+        assert_eq!(runtime.container_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_container_removed_before_restart() {
+        let (w, runtime) = fake_worker_with(Config {
+            restart_backoff: Duration::ZERO,
+            ..test_config()
+        });
+        let w = Arc::new(w);
+        let t = Task {
+            restart_policy: task::RestartPolicy::Always,
+            liveness: Some(task::Probe {
+                action: task::ProbeAction::Exec {
+                    cmd: "pg_isready".to_string(),
+                },
+                interval_secs: 0,
+                timeout_secs: 1,
+                failure_threshold: 1,
+            }),
+            ..scheduled_task()
+        };
+        w.add_task(t.clone());
+        let first = w.run_task().await.unwrap().unwrap();
+
+        runtime.set_exec_exit_code(&first.id, 1);
+        runtime.set_delay(Duration::from_millis(200));
+        let check = tokio::spawn({
+            let w = w.clone();
+            async move { w.check_health().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // nothing is restarted or given back while the container is stopping
+        assert_eq!(w.db.lock().unwrap()[&t.id].state, task::State::Running);
+        assert!(w.restarts.lock().unwrap().is_empty());
+        w.inspect_tasks().await;
+        assert_eq!(w.db.lock().unwrap()[&t.id].state, task::State::Running);
+
+        check.await.unwrap();
+        let restarting = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(restarting.state, task::State::Restarting);
+        assert!(matches!(
+            restarting.failure_reason,
+            Some(FailureReason::Unhealthy(_))
+        ));
+        assert_eq!(runtime.container_count(), 0);
+    }
+
+    /// Counts the puts it passes on to a `MemoryStore`.
+    #[derive(Debug, Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        puts: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CountingStore {
+        fn puts(&self) -> usize {
+            self.puts.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl TaskStore for CountingStore {
+        fn put(&self, t: &Task) -> std::io::Result<()> {
+            self.puts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.put(t)
+        }
+
+        fn load(&self) -> std::io::Result<Vec<Task>> {
+            self.inner.load()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_health_stores_probe_results_only() {
+        let store = Arc::new(CountingStore::default());
+        let (w, _) = fake_worker();
+        let w = w.with_store(store.clone());
+        let probed = Task {
+            liveness: Some(task::Probe {
+                action: task::ProbeAction::Exec {
+                    cmd: "true".to_string(),
+                },
+                interval_secs: 3600,
+                timeout_secs: 1,
+                failure_threshold: 1,
+            }),
+            ..scheduled_task()
+        };
+        w.add_task(probed.clone());
+        w.add_task(Task {
+            name: "unprobed-task".to_string(),
+            ..scheduled_task()
+        });
+        w.run_task().await.unwrap();
+        w.run_task().await.unwrap();
+        // started long enough ago for the first probe to be due
+        w.db.lock().unwrap().get_mut(&probed.id).unwrap().start_time =
+            Utc::now() - chrono::Duration::hours(2);
+
+        let puts = store.puts();
+        w.check_health().await;
+        assert_eq!(store.puts(), puts + 1);
+        // the probe isn't due again and the other task has none
+        w.check_health().await;
+        assert_eq!(store.puts(), puts + 1);
+    }
+
+    #[tokio::test]
+    async fn test_restart_policy() {
+        let (w, runtime) = fake_worker_with(Config {
+            restart_backoff: Duration::ZERO,
+            ..test_config()
+        });
+        let t = Task {
+            restart_policy: task::RestartPolicy::OnFailure { max_retries: 1 },
+            ..scheduled_task()
+        };
+        w.add_task(t.clone());
+        let first = w.run_task().await.unwrap().unwrap();
+
+        runtime.exit(&first.id, 1);
+        w.inspect_tasks().await;
+        let restarting = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(restarting.state, task::State::Restarting);
+        assert_eq!(restarting.restart_count, 1);
+        assert_eq!(runtime.container_count(), 0);
+
+        w.queue_due_restarts();
+        let second = w.run_task().await.unwrap().unwrap();
+        assert_ne!(second.id, first.id);
+        assert_eq!(w.db.lock().unwrap()[&t.id].state, task::State::Running);
+
+        // out of retries
+        runtime.exit(&second.id, 1);
+        w.inspect_tasks().await;
+        let failed = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(failed.state, task::State::Failed);
+        assert_eq!(failed.restart_count, 1);
+        assert!(w.restarts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        let (w, _) = fake_worker_with(Config {
+            restart_backoff: Duration::from_secs(1),
+            restart_backoff_max: Duration::from_secs(10),
+            ..test_config()
+        });
+        let delays: Vec<_> = (1..=5).map(|n| w.restart_delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_pull_error_fails_task() {
+        let (w, runtime) = fake_worker();
+        let t = scheduled_task();
+
+        let err = "manifest for strm/helloworld-http:nope not found".to_string();
+        runtime.fail_next_pull(RuntimeError::ImagePull(err.clone()));
+        w.add_task(t.clone());
+        assert!(w.run_task().await.is_err());
+
+        let failed = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(failed.state, task::State::Failed);
+        assert_eq!(failed.failure_reason, Some(FailureReason::ImagePull(err)));
+        assert!(w.pulls.lock().unwrap().is_empty());
+        assert_eq!(runtime.container_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pull_uses_registry_credential() {
+        let auth = RegistryAuth {
+            name: "ci".to_string(),
+            username: "bot".to_string(),
+            password: "secret".to_string(),
+            identity_token: None,
+        };
+        let (w, runtime) = fake_worker_with(Config {
+            registry_auth: HashMap::from([("ghcr.io".to_string(), auth.clone())]),
+            ..test_config()
+        });
+
+        let private = Task {
+            image: "ghcr.io/org/app:1.0".to_string(),
+            registry_credential: Some("ci".to_string()),
+            ..scheduled_task()
+        };
+        let public = Task {
+            name: "public-task".to_string(),
+            ..scheduled_task()
+        };
+        let unknown = Task {
+            id: Uuid::new_v4(),
+            name: "unknown-credential".to_string(),
+            registry_credential: Some("prod".to_string()),
+            ..private.clone()
+        };
+
+        for t in [&private, &public, &unknown] {
+            w.add_task(t.clone());
+            let _ = w.run_task().await;
+        }
+
+        let pulls = runtime.pulls();
+        assert_eq!(pulls.len(), 2);
+        assert_eq!(pulls[0].registry_auth, Some(auth));
+        assert_eq!(pulls[1].registry_auth, None);
+
+        let failed = w.db.lock().unwrap()[&unknown.id].clone();
+        assert!(matches!(
+            failed.failure_reason,
+            Some(FailureReason::ImagePull(_))
+        ));
+        let json = serde_json::to_string(&w.db.lock().unwrap()[&private.id]).unwrap();
+        assert!(!json.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_runtime_requeues_task() {
+        let (w, runtime) = fake_worker();
+        let t = scheduled_task();
+
+        runtime.fail_next_run(RuntimeError::DaemonUnreachable("no socket".to_string()));
+        w.add_task(t.clone());
+        assert!(w.run_task().await.is_err());
+        assert_eq!(w.queue.lock().unwrap().len(), 1);
+
+        w.run_task().await.unwrap();
+        let running = w.db.lock().unwrap().get(&t.id).cloned().unwrap();
+        assert_eq!(running.state, task::State::Running);
+        assert_eq!(running.attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_start_fails_then_succeeds() {
+        let (w, runtime) = fake_worker();
+        let t = scheduled_task();
+
+        // created, but the daemon went away before it started
+        runtime.fail_next_start(RuntimeError::DaemonUnreachable("no socket".to_string()));
+        w.add_task(t.clone());
+        assert!(w.run_task().await.is_err());
+        assert_eq!(runtime.container_count(), 0);
+
+        // nothing left behind under the task's name
+        let handle = w.run_task().await.unwrap().unwrap();
+        assert_eq!(handle.id, "fake-2");
+        let running = w.db.lock().unwrap()[&t.id].clone();
+        assert_eq!(running.state, task::State::Running);
+        assert_eq!(runtime.container_count(), 1);
+    }
+}